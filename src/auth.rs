@@ -0,0 +1,582 @@
+use crate::error::ApiError;
+use crate::users::{self, CSRF_HEADER, SESSION_COOKIE, SignedIn};
+use chrono::{DateTime, Utc};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder, Set,
+    sea_query::Expr,
+};
+use sea_orm_migration::{
+    prelude::{Iden, MigrationName, MigrationTrait, SchemaManager, Table},
+    sea_query,
+    sea_query::ColumnDef,
+};
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use warp::{
+    Filter, Rejection, Reply,
+    http::{Method, StatusCode},
+};
+
+const TOKEN_PREFIX: &str = "stk_";
+const DISPLAY_PREFIX_LEN: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Scope {
+    #[serde(rename = "products:read")]
+    ProductsRead,
+    #[serde(rename = "products:write")]
+    ProductsWrite,
+    #[serde(rename = "card:read")]
+    CardRead,
+    /// Only the age check, which releases no card data.
+    #[serde(rename = "card:age")]
+    CardAge,
+    /// Everything, including webhooks, origins, privacy and tokens.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::ProductsRead,
+        Scope::ProductsWrite,
+        Scope::CardRead,
+        Scope::CardAge,
+        Scope::Admin,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ProductsRead => "products:read",
+            Scope::ProductsWrite => "products:write",
+            Scope::CardRead => "card:read",
+            Scope::CardAge => "card:age",
+            Scope::Admin => "admin",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scope| scope.as_str() == s)
+    }
+
+    /// Whether holding `self` grants `scope`. `card:read` already returns the
+    /// birth date, so it grants `card:age` too.
+    pub fn covers(&self, scope: Scope) -> bool {
+        *self == scope || *self == Scope::Admin || (*self == Scope::CardRead && scope == Scope::CardAge)
+    }
+
+    /// Scopes that expose the card holder. Anonymous local callers need an
+    /// approved `Origin` for them, so that a page that reaches this server
+    /// without sending one cannot read cards.
+    fn needs_origin(&self) -> bool {
+        matches!(self, Scope::CardRead | Scope::CardAge)
+    }
+
+    /// Scopes that only a token or a signed-in user gets, even from this
+    /// machine.
+    fn needs_user(&self) -> bool {
+        matches!(self, Scope::ProductsWrite | Scope::Admin)
+    }
+}
+
+// API token entity
+pub mod api_token {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "api_tokens")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub name: String,
+        /// SHA-256 of the token; the token itself is only shown when issued.
+        #[sea_orm(unique)]
+        #[serde(skip)]
+        pub token_hash: String,
+        /// First characters of the token, to recognise it in listings.
+        pub prefix: String,
+        /// Comma-separated scopes.
+        pub scopes: String,
+        pub expires_at: Option<DateTimeUtc>,
+        pub created_at: DateTimeUtc,
+        pub last_used_at: Option<DateTimeUtc>,
+        pub revoked_at: Option<DateTimeUtc>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+impl api_token::Model {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes
+            .split(',')
+            .filter_map(Scope::parse)
+            .any(|s| s.covers(scope))
+    }
+
+    fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.revoked_at.is_none() && self.expires_at.is_none_or(|at| at > now)
+    }
+}
+
+// Migration
+pub struct ApiTokenMigration;
+
+impl MigrationName for ApiTokenMigration {
+    fn name(&self) -> &str {
+        "m0007_create_api_tokens"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for ApiTokenMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ApiTokens::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(ApiTokens::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(ApiTokens::Name).string().not_null())
+                    .col(ColumnDef::new(ApiTokens::TokenHash).string().not_null().unique_key())
+                    .col(ColumnDef::new(ApiTokens::Prefix).string().not_null())
+                    .col(ColumnDef::new(ApiTokens::Scopes).string().not_null())
+                    .col(ColumnDef::new(ApiTokens::ExpiresAt).timestamp_with_time_zone())
+                    .col(ColumnDef::new(ApiTokens::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(ApiTokens::LastUsedAt).timestamp_with_time_zone())
+                    .col(ColumnDef::new(ApiTokens::RevokedAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_table(Table::drop().table(ApiTokens::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum ApiTokens {
+    Table,
+    Id,
+    Name,
+    TokenHash,
+    Prefix,
+    Scopes,
+    ExpiresAt,
+    CreatedAt,
+    LastUsedAt,
+    RevokedAt,
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Creates a token and returns its row and the token, which is not stored.
+pub async fn issue(
+    db: &DatabaseConnection,
+    name: &str,
+    scopes: &[Scope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(api_token::Model, String), DbErr> {
+    let token = format!("{}{}", TOKEN_PREFIX, hex::encode(rand::random::<[u8; 32]>()));
+    let row = api_token::ActiveModel {
+        name: Set(name.to_string()),
+        token_hash: Set(hash_token(&token)),
+        prefix: Set(token[..DISPLAY_PREFIX_LEN].to_string()),
+        scopes: Set(scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(",")),
+        expires_at: Set(expires_at),
+        created_at: Set(Utc::now()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok((row, token))
+}
+
+pub async fn list(db: &DatabaseConnection) -> Result<Vec<api_token::Model>, DbErr> {
+    api_token::Entity::find()
+        .order_by_asc(api_token::Column::Id)
+        .all(db)
+        .await
+}
+
+/// Returns false when there is no such active token.
+pub async fn revoke(db: &DatabaseConnection, id: i32) -> Result<bool, DbErr> {
+    let res = api_token::Entity::update_many()
+        .col_expr(api_token::Column::RevokedAt, Expr::value(Some(Utc::now())))
+        .filter(api_token::Column::Id.eq(id))
+        .filter(api_token::Column::RevokedAt.is_null())
+        .exec(db)
+        .await?;
+    Ok(res.rows_affected > 0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMode {
+    /// No checks.
+    Off,
+    /// Loopback clients pass without a token; others need one.
+    Remote,
+    /// Every request needs a token or a signed-in user.
+    All,
+}
+
+impl AuthMode {
+    /// `API_AUTH=off|remote|all`, default `remote`.
+    pub fn from_env() -> Self {
+        match std::env::var("API_AUTH").as_deref() {
+            Ok("off") => AuthMode::Off,
+            Ok("all") => AuthMode::All,
+            _ => AuthMode::Remote,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TokenQuery {
+    access_token: Option<String>,
+}
+
+/// Who is making a request. `origin` is whether an anonymous request sent an
+/// `Origin` header, which `origins::protect` only lets through when approved.
+#[derive(Clone, Debug)]
+pub enum Principal {
+    Anonymous { local: bool, origin: bool },
+    Token(api_token::Model),
+    User(SignedIn),
+}
+
+#[derive(Clone)]
+pub struct Auth {
+    db: DatabaseConnection,
+    mode: AuthMode,
+    secure_cookies: bool,
+}
+
+impl Auth {
+    pub fn new(db: DatabaseConnection, mode: AuthMode) -> Self {
+        Self {
+            db,
+            mode,
+            secure_cookies: false,
+        }
+    }
+
+    /// Marks the session cookies `Secure`, for when HTTPS is served.
+    pub fn with_secure_cookies(mut self, secure: bool) -> Self {
+        self.secure_cookies = secure;
+        self
+    }
+
+    pub fn secure_cookies(&self) -> bool {
+        self.secure_cookies
+    }
+
+    pub fn db(&self) -> &DatabaseConnection {
+        &self.db
+    }
+
+    pub fn mode(&self) -> AuthMode {
+        self.mode
+    }
+
+    /// Resolves the caller. The token comes from `Authorization: Bearer`, or
+    /// `?access_token=` for EventSource and WebSocket clients, which cannot
+    /// set headers; without one the session cookie is used. Cookie requests
+    /// that change state must echo the session's CSRF token in
+    /// `X-CSRF-Token`.
+    pub fn principal(&self) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone + use<> {
+        let auth = self.clone();
+        warp::addr::remote()
+            .and(warp::method())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<TokenQuery>().or(warp::any().map(|| TokenQuery { access_token: None })).unify())
+            .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+            .and(warp::header::optional::<String>(CSRF_HEADER))
+            .and(warp::header::optional::<String>("origin"))
+            .and_then(
+                move |remote: Option<SocketAddr>,
+                      method: Method,
+                      header: Option<String>,
+                      query: TokenQuery,
+                      cookie: Option<String>,
+                      csrf: Option<String>,
+                      origin: Option<String>| {
+                    let auth = auth.clone();
+                    async move {
+                        let token = header
+                            .as_deref()
+                            .and_then(|h| h.strip_prefix("Bearer "))
+                            .map(str::to_string)
+                            .or(query.access_token);
+                        auth.resolve(remote, method, token, cookie, csrf, origin.is_some()).await
+                    }
+                },
+            )
+    }
+
+    /// Passes requests allowed `scope`.
+    pub fn require(&self, scope: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone + use<> {
+        self.guard(scope, false)
+    }
+
+    /// `require` for HTML pages: callers that are not signed in are sent to
+    /// the login page.
+    pub fn require_page(&self, scope: Scope) -> impl Filter<Extract = (), Error = Rejection> + Clone + use<> {
+        self.guard(scope, true)
+    }
+
+    fn guard(&self, scope: Scope, page: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone + use<> {
+        let auth = self.clone();
+        self.principal()
+            .and_then(move |principal: Principal| {
+                let auth = auth.clone();
+                async move {
+                    auth.allow(&principal, scope).await.map_err(|error| match error {
+                        ApiError::Unauthorized(_) if page => Rejection::from(ApiError::LoginRequired),
+                        error => Rejection::from(error),
+                    })
+                }
+            })
+            .untuple_one()
+    }
+
+    async fn resolve(
+        &self,
+        remote: Option<SocketAddr>,
+        method: Method,
+        token: Option<String>,
+        cookie: Option<String>,
+        csrf: Option<String>,
+        origin: bool,
+    ) -> Result<Principal, Rejection> {
+        let local = remote.is_some_and(|addr| addr.ip().is_loopback());
+        if let Some(token) = token.filter(|_| self.mode != AuthMode::Off) {
+            return self.token(&token).await.map(Principal::Token);
+        }
+        let Some(cookie) = cookie else {
+            return Ok(Principal::Anonymous { local, origin });
+        };
+        let signed_in = users::find_session(&self.db, &cookie)
+            .await
+            .map_err(ApiError::from)?;
+        // An expired session cookie is the same as none.
+        let Some(signed_in) = signed_in else {
+            return Ok(Principal::Anonymous { local, origin });
+        };
+        let safe = matches!(method, Method::GET | Method::HEAD | Method::OPTIONS);
+        if !safe && csrf.as_deref() != Some(signed_in.session.csrf_token.as_str()) {
+            return Err(ApiError::Forbidden("CSRF token missing or invalid".to_string()).into());
+        }
+        Ok(Principal::User(signed_in))
+    }
+
+    async fn token(&self, token: &str) -> Result<api_token::Model, Rejection> {
+        let found = api_token::Entity::find()
+            .filter(api_token::Column::TokenHash.eq(hash_token(token)))
+            .one(&self.db)
+            .await
+            .map_err(ApiError::from)?;
+        let now = Utc::now();
+        let Some(row) = found.filter(|row| row.is_active(now)) else {
+            return Err(ApiError::Unauthorized("invalid or expired token".to_string()).into());
+        };
+
+        let (db, id) = (self.db.clone(), row.id);
+        tokio::spawn(async move {
+            let _ = api_token::Entity::update_many()
+                .col_expr(api_token::Column::LastUsedAt, Expr::value(Some(now)))
+                .filter(api_token::Column::Id.eq(id))
+                .exec(&db)
+                .await;
+        });
+        Ok(row)
+    }
+
+    /// Card data needs a token, a signed-in user or an approved origin even
+    /// from this machine; product edits and admin endpoints, which include
+    /// tokens and webhooks, need a signed-in user or a token.
+    async fn allow(&self, principal: &Principal, scope: Scope) -> Result<(), ApiError> {
+        let allowed = match (self.mode, principal) {
+            (AuthMode::Off, _) => true,
+            (_, Principal::Token(token)) => token.has_scope(scope),
+            (_, Principal::User(signed_in)) => signed_in.user.role.grants(scope),
+            (AuthMode::Remote, Principal::Anonymous { local: true, origin }) => {
+                if scope.needs_origin() && !origin {
+                    return Err(ApiError::Unauthorized("token or approved origin required".to_string()));
+                }
+                if scope.needs_user() {
+                    return Err(ApiError::Unauthorized("sign in required".to_string()));
+                }
+                true
+            }
+            (_, Principal::Anonymous { .. }) => return Err(ApiError::Unauthorized("token required".to_string())),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden(format!("not allowed without the {} scope", scope.as_str())))
+        }
+    }
+}
+
+// Routes
+#[derive(serde::Deserialize)]
+struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(serde::Serialize)]
+struct IssuedToken {
+    #[serde(flatten)]
+    row: api_token::Model,
+    token: String,
+}
+
+/// `GET/POST tokens`, `DELETE tokens/{id}`; admin scope.
+pub fn routes(auth: Auth) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = auth.require(Scope::Admin);
+    let auth_filter = warp::any().map(move || auth.clone());
+
+    let list = warp::path!("tokens")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(auth_filter.clone())
+        .and_then(list_tokens);
+
+    let create = warp::path!("tokens")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(auth_filter.clone())
+        .and_then(create_token);
+
+    let delete = warp::path!("tokens" / i32)
+        .and(warp::delete())
+        .and(admin)
+        .and(auth_filter)
+        .and_then(revoke_token);
+
+    list.or(create).or(delete)
+}
+
+async fn list_tokens(auth: Auth) -> Result<impl Reply, Rejection> {
+    let tokens = list(&auth.db).await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&tokens))
+}
+
+async fn create_token(new: NewToken, auth: Auth) -> Result<impl Reply, Rejection> {
+    if new.name.trim().is_empty() || new.scopes.is_empty() {
+        return Err(ApiError::BadRequest("name and at least one scope are required".to_string()).into());
+    }
+    let (row, token) = issue(&auth.db, new.name.trim(), &new.scopes, new.expires_at)
+        .await
+        .map_err(ApiError::from)?;
+    let body = IssuedToken { row, token };
+    Ok(warp::reply::with_status(warp::reply::json(&body), StatusCode::CREATED))
+}
+
+async fn revoke_token(id: i32, auth: Auth) -> Result<impl Reply, Rejection> {
+    if !revoke(&auth.db, id).await.map_err(ApiError::from)? {
+        return Err(ApiError::NotFound("token not found".to_string()).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrator;
+
+    fn token_with(scopes: &str) -> api_token::Model {
+        api_token::Model {
+            id: 1,
+            name: "till".to_string(),
+            token_hash: String::new(),
+            prefix: String::new(),
+            scopes: scopes.to_string(),
+            expires_at: None,
+            created_at: Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        }
+    }
+
+    #[test]
+    fn admin_scope_covers_the_others() {
+        let reader = token_with("products:read,card:read");
+        assert!(reader.has_scope(Scope::ProductsRead));
+        assert!(reader.has_scope(Scope::CardRead));
+        assert!(reader.has_scope(Scope::CardAge));
+        assert!(!reader.has_scope(Scope::ProductsWrite));
+        assert!(!reader.has_scope(Scope::Admin));
+
+        let age_only = token_with("card:age");
+        assert!(age_only.has_scope(Scope::CardAge));
+        assert!(!age_only.has_scope(Scope::CardRead));
+
+        let admin = token_with("admin");
+        assert!(Scope::ALL.into_iter().all(|scope| admin.has_scope(scope)));
+
+        let unknown = token_with("products:*,,");
+        assert!(Scope::ALL.into_iter().all(|scope| !unknown.has_scope(scope)));
+    }
+
+    #[tokio::test]
+    async fn anonymous_local_card_reads_need_an_origin() {
+        let auth = Auth::new(migrator::test_db().await, AuthMode::Remote);
+        let without = Principal::Anonymous { local: true, origin: false };
+        let with = Principal::Anonymous { local: true, origin: true };
+
+        assert!(auth.allow(&without, Scope::ProductsRead).await.is_ok());
+        assert!(matches!(auth.allow(&without, Scope::CardRead).await, Err(ApiError::Unauthorized(_))));
+        assert!(matches!(auth.allow(&without, Scope::CardAge).await, Err(ApiError::Unauthorized(_))));
+        assert!(auth.allow(&with, Scope::CardRead).await.is_ok());
+        assert!(auth.allow(&with, Scope::CardAge).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn anonymous_local_callers_never_get_admin_scopes() {
+        // No users yet, as on a fresh install
+        let auth = Auth::new(migrator::test_db().await, AuthMode::Remote);
+        let local = Principal::Anonymous { local: true, origin: true };
+        for scope in [Scope::Admin, Scope::ProductsWrite] {
+            assert!(matches!(auth.allow(&local, scope).await, Err(ApiError::Unauthorized(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn revoked_and_expired_tokens_are_refused() {
+        let db = migrator::test_db().await;
+        let auth = Auth::new(db.clone(), AuthMode::All);
+
+        let (row, token) = issue(&db, "till", &[Scope::CardRead], None).await.unwrap();
+        assert!(token.starts_with(TOKEN_PREFIX));
+        assert_eq!(row.prefix, token[..DISPLAY_PREFIX_LEN]);
+        assert_eq!(auth.token(&token).await.unwrap().id, row.id);
+        assert!(auth.token("stk_unknown").await.is_err());
+
+        assert!(revoke(&db, row.id).await.unwrap());
+        assert!(!revoke(&db, row.id).await.unwrap());
+        assert!(auth.token(&token).await.is_err());
+
+        let expired = Utc::now() - chrono::Duration::minutes(1);
+        let (_, token) = issue(&db, "old", &[Scope::CardRead], Some(expired)).await.unwrap();
+        assert!(auth.token(&token).await.is_err());
+    }
+}
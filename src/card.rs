@@ -1,23 +1,190 @@
+use crate::events::{CardEvent, EventBus};
 use crate::log;
+use chrono::{DateTime, Local};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
 use pcsc::{Context, Error, Scope};
 use std::{
-    sync::{Arc, Mutex},
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    sync::{
+        Arc, Mutex, MutexGuard, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use crate::thaiid::thai_id;
+use crate::thaiid::thai_id::{self, Field, ThaiIdInfo};
+
+// Serialises APDU exchanges between the background listener and on-demand
+// reads; a Thai ID read is a sequence of command/GET RESPONSE pairs that must
+// not interleave.
+static READER_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_readers() -> MutexGuard<'static, ()> {
+    READER_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reads of an inserted card that may fail (e.g. it was pulled out half way)
+/// before the listener gives up on it until it is removed.
+const MAX_READ_ATTEMPTS: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReaderState {
+    Empty,
+    Present,
+    Error,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct ReaderStatus {
+    pub reader: String,
+    pub state: ReaderState,
+    pub updated_at: DateTime<Local>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub card: Option<CardRead>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CardRead {
+    pub read_at: DateTime<Local>,
+    pub info: ThaiIdInfo,
+}
+
+// Last known state of every attached reader, written by the listener thread
+// and read by the HTTP handlers.
+#[derive(Clone, Default)]
+pub struct CardStore {
+    readers: Arc<RwLock<BTreeMap<String, ReaderStatus>>>,
+}
+
+impl CardStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn readers(&self) -> Vec<ReaderStatus> {
+        self.readers.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn reader(&self, name: &str) -> Option<ReaderStatus> {
+        self.readers.read().unwrap().get(name).cloned()
+    }
+
+    /// The reader holding the most recently read card, falling back to the
+    /// first attached reader when no card is present.
+    pub fn current(&self) -> Option<ReaderStatus> {
+        let readers = self.readers.read().unwrap();
+        readers
+            .values()
+            .filter(|r| r.card.is_some())
+            .max_by_key(|r| r.card.as_ref().map(|c| c.read_at))
+            .or_else(|| readers.values().next())
+            .cloned()
+    }
+
+    /// Drops the cached read of `cid` from any reader. The reader itself keeps
+    /// its state so the card is not re-read while it stays inserted.
+    pub fn forget_cid(&self, cid: &str) -> usize {
+        let mut forgotten = 0;
+        for status in self.readers.write().unwrap().values_mut() {
+            if status.card.as_ref().is_some_and(|c| c.info.cid == cid) {
+                status.card = None;
+                forgotten += 1;
+            }
+        }
+        forgotten
+    }
+
+    fn is_present(&self, name: &str) -> bool {
+        self.readers
+            .read()
+            .unwrap()
+            .get(name)
+            .is_some_and(|r| r.state != ReaderState::Empty)
+    }
+
+    fn set(&self, name: &str, state: ReaderState, card: Option<CardRead>, error: Option<String>) {
+        self.readers.write().unwrap().insert(
+            name.to_string(),
+            ReaderStatus {
+                reader: name.to_string(),
+                state,
+                updated_at: Local::now(),
+                error,
+                card,
+            },
+        );
+    }
+
+    /// Drops readers that are no longer attached and returns their names.
+    fn retain(&self, names: &[String]) -> Vec<String> {
+        let mut readers = self.readers.write().unwrap();
+        let gone: Vec<String> = readers
+            .keys()
+            .filter(|k| !names.contains(k))
+            .cloned()
+            .collect();
+        for name in &gone {
+            readers.remove(name);
+        }
+        gone
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub poll_interval: Duration,
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(3),
+        }
+    }
+}
+
+impl ListenerConfig {
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Some(ms) = std::env::var("CARD_POLL_INTERVAL_MS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+        {
+            config.poll_interval = Duration::from_millis(ms);
+        }
+        config
+    }
+}
+
+enum Control {
+    Stop,
+    Pause,
+    Resume,
+}
 
 pub struct CardListener {
     handle: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
-    stop_flag: Arc<Mutex<bool>>,
+    control: Arc<Mutex<Option<Sender<Control>>>>,
+    config: ListenerConfig,
+    paused: Arc<AtomicBool>,
+    store: CardStore,
+    events: EventBus,
 }
 
 impl CardListener {
-    pub fn new() -> Self {
+    pub fn new(config: ListenerConfig, store: CardStore, events: EventBus) -> Self {
         Self {
             handle: Arc::new(Mutex::new(None)),
-            stop_flag: Arc::new(Mutex::new(false)),
+            control: Arc::new(Mutex::new(None)),
+            config,
+            paused: Arc::new(AtomicBool::new(false)),
+            store,
+            events,
         }
     }
 
@@ -26,73 +193,326 @@ impl CardListener {
         if h.is_some() {
             return;
         }
-        *self.stop_flag.lock().unwrap() = false;
 
-        let stop = Arc::clone(&self.stop_flag);
-        let handle = thread::spawn(move || {
-            let ctx = match Context::establish(Scope::User) {
-                Ok(c) => c,
-                Err(e) => {
-                    log::write_log_line(&format!("PCSC init failed: {}", e));
-                    return;
-                }
-            };
+        let (tx, rx) = crossbeam_channel::unbounded();
+        *self.control.lock().unwrap() = Some(tx);
+
+        let config = self.config.clone();
+        let paused = self.paused.load(Ordering::SeqCst);
+        let store = self.store.clone();
+        let events = self.events.clone();
+        let handle = thread::spawn(move || run(rx, config, paused, store, events));
+
+        *h = Some(handle);
+    }
+
+    pub fn stop(&self) {
+        self.send(Control::Stop);
+        self.control.lock().unwrap().take();
+        if let Some(t) = self.handle.lock().unwrap().take() {
+            let _ = t.join();
+        }
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+        self.send(Control::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+        self.send(Control::Resume);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn send(&self, msg: Control) {
+        if let Some(tx) = self.control.lock().unwrap().as_ref() {
+            let _ = tx.send(msg);
+        }
+    }
+}
+
+fn run(
+    rx: Receiver<Control>,
+    config: ListenerConfig,
+    mut paused: bool,
+    store: CardStore,
+    events: EventBus,
+) {
+    let ctx = match Context::establish(Scope::User) {
+        Ok(c) => c,
+        Err(e) => {
+            log::write_log_line(&format!("PCSC init failed: {}", e));
+            return;
+        }
+    };
+
+    let mut buf = [0u8; 2048];
+    let mut failures = HashMap::new();
+
+    loop {
+        if !paused {
+            poll_readers(&ctx, &mut buf, &store, &events, &mut failures);
+        }
 
-            let mut buf = [0u8; 2048];
+        // Block on the control channel instead of sleeping so that stop,
+        // pause and resume take effect immediately.
+        let msg = if paused {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(config.poll_interval)
+        };
 
-            loop {
-                if *stop.lock().unwrap() {
-                    log::write_log_line("Card listener stopped");
-                    break;
+        match msg {
+            Ok(Control::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                log::write_log_line("Card listener stopped");
+                break;
+            }
+            Ok(Control::Pause) => {
+                log::write_log_line("Card listener paused");
+                paused = true;
+            }
+            Ok(Control::Resume) => {
+                log::write_log_line("Card listener resumed");
+                paused = false;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+    }
+}
+
+/// Whether a card that connected on a reader in `state` should be read:
+/// once per insertion, and again after a failed read up to
+/// `MAX_READ_ATTEMPTS` in all.
+fn should_read(state: Option<ReaderState>, failures: u32) -> bool {
+    match state {
+        Some(ReaderState::Present) => false,
+        Some(ReaderState::Error) => failures < MAX_READ_ATTEMPTS,
+        Some(ReaderState::Empty) | None => true,
+    }
+}
+
+/// `failures` counts failed reads of the card in each reader, so a card in
+/// `Error` is retried a few times rather than never.
+fn poll_readers(
+    ctx: &Context,
+    buf: &mut [u8],
+    store: &CardStore,
+    events: &EventBus,
+    failures: &mut HashMap<String, u32>,
+) {
+    match ctx.list_readers(buf) {
+        Ok(names) => {
+            let mut seen = Vec::new();
+            for reader_cstr in names {
+                let name = reader_cstr.to_string_lossy().into_owned();
+                seen.push(name.clone());
+                if store.reader(&name).is_none() {
+                    log::write_log_line(&format!("Reader found: {}", name));
+                    store.set(&name, ReaderState::Empty, None, None);
+                    events.publish(CardEvent::ReaderAttached { reader: name.clone() });
                 }
 
-                match ctx.list_readers(&mut buf) {
-                    Ok(mut names) => {
-                        while let Some(reader_cstr) = names.next() {
-                            let name = reader_cstr.to_string_lossy().into_owned();
-                            log::write_log_line(&format!("Reader found: {}", name));
-
-                            match ctx.connect(reader_cstr, pcsc::ShareMode::Shared, pcsc::Protocols::ANY) {
-                                Ok(mut _card) => {
-                                    log::write_log_line("Card inserted, reading...");
-                                    let info = thai_id::read_thai_id();
-                                    log::write_log_line(&format!("CID: {}", info.cid));
-                                    log::write_log_line(&format!("TH Name: {}", info.th_name));
-                                    log::write_log_line(&format!("EN Name: {}", info.en_name));
-                                    log::write_log_line(&format!("Birth: {}", info.birth));
-                                    log::write_log_line(&format!("Gender: {}", info.gender));
-                                    log::write_log_line(&format!("Issuer: {}", info.issuer));
-                                    log::write_log_line(&format!("Issue Date: {}", info.issue_date));
-                                    log::write_log_line(&format!("Expire Date: {}", info.expire_date));
-                                    log::write_log_line(&format!("Address: {}", info.address));
-                                    log::write_log_line(&format!("Photo (partial): {}...", &info.photo_base64[..60]));
-                                }
-                                Err(Error::NoSmartcard) => {}
-                                Err(e) => {
-                                    log::write_log_line(&format!("Card connect error: {}", e));
-                                }
+                // Connecting exchanges no APDUs, so the lock is only taken
+                // for the read and an on-demand read is not held up by every
+                // reader being polled.
+                let state = store.reader(&name).map(|r| r.state);
+                let failed = failures.get(&name).copied().unwrap_or_default();
+                match ctx.connect(reader_cstr, pcsc::ShareMode::Shared, pcsc::Protocols::ANY) {
+                    // The card stays current until it is removed.
+                    Ok(_) if !should_read(state, failed) => {}
+                    Ok(card) => {
+                        if state == Some(ReaderState::Error) {
+                            log::write_log_line(&format!("Retrying card read on {}", name));
+                        } else {
+                            log::write_log_line("Card inserted, reading...");
+                            events.publish(CardEvent::CardInserted { reader: name.clone() });
+                        }
+                        events.publish(CardEvent::ReadStarted { reader: name.clone() });
+                        let result = {
+                            let _guard = lock_readers();
+                            thai_id::read_thai_id(&card, &Field::ALL)
+                        };
+                        match result {
+                            Ok(info) => {
+                                failures.remove(&name);
+                                log_card(&info);
+                                let read = CardRead { read_at: Local::now(), info: info.clone() };
+                                store.set(&name, ReaderState::Present, Some(read), None);
+                                events.publish(CardEvent::ReadCompleted { reader: name.clone(), card: Box::new(info) });
+                            }
+                            Err(e) => {
+                                *failures.entry(name.clone()).or_default() += 1;
+                                log::write_log_line(&format!("Card read failed: {}", e));
+                                store.set(&name, ReaderState::Error, None, Some(e.clone()));
+                                events.publish(CardEvent::ReadFailed { reader: name.clone(), error: e });
                             }
                         }
                     }
-                    Err(Error::NoReadersAvailable) => {
-                        log::write_log_line("No readers available");
+                    Err(Error::NoSmartcard) | Err(Error::RemovedCard) => {
+                        failures.remove(&name);
+                        if store.is_present(&name) {
+                            log::write_log_line(&format!("Card removed: {}", name));
+                            store.set(&name, ReaderState::Empty, None, None);
+                            events.publish(CardEvent::CardRemoved { reader: name.clone() });
+                        }
                     }
                     Err(e) => {
-                        log::write_log_line(&format!("Reader list failed: {}", e));
+                        log::write_log_line(&format!("Card connect error: {}", e));
                     }
                 }
-
-                thread::sleep(Duration::from_secs(3));
             }
-        });
+            failures.retain(|name, _| seen.contains(name));
+            for reader in store.retain(&seen) {
+                log::write_log_line(&format!("Reader detached: {}", reader));
+                events.publish(CardEvent::ReaderDetached { reader });
+            }
+        }
+        Err(Error::NoReadersAvailable) => {
+            failures.clear();
+            for reader in store.retain(&[]) {
+                log::write_log_line(&format!("Reader detached: {}", reader));
+                events.publish(CardEvent::ReaderDetached { reader });
+            }
+            log::write_log_line("No readers available");
+        }
+        Err(e) => {
+            log::write_log_line(&format!("Reader list failed: {}", e));
+        }
+    }
+}
 
-        *h = Some(handle);
+/// Writes one line per `log::CARD_FIELDS` label, as one block so erasure can
+/// find every line of a read.
+fn log_card(info: &ThaiIdInfo) {
+    let policy = log::redaction();
+    let fields = [
+        log::card_key(&info.cid),
+        log::redact_cid(policy.cid, &info.cid),
+        log::redact(policy.names, &info.th_name),
+        log::redact(policy.names, &info.en_name),
+        log::redact(policy.details, &info.birth),
+        log::redact(policy.details, &info.gender),
+        log::redact(policy.details, &info.issuer),
+        log::redact(policy.details, &info.issue_date),
+        log::redact(policy.details, &info.expire_date),
+        log::redact(policy.details, &info.address),
+        policy
+            .photo
+            .then(|| format!("{}...", &info.photo_base64[..60.min(info.photo_base64.len())])),
+    ];
+    let lines: Vec<String> = log::CARD_FIELDS
+        .iter()
+        .zip(fields)
+        .filter_map(|(label, value)| Some(format!("{}: {}", label, value?)))
+        .collect();
+    log::write_log_lines(&lines);
+}
+
+#[derive(Debug)]
+pub enum ReadError {
+    Timeout,
+    NoReaders,
+    UnknownReader(String),
+    Failed(String),
+}
+
+impl std::fmt::Display for ReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::Timeout => write!(f, "timed out waiting for a card"),
+            ReadError::NoReaders => write!(f, "no readers available"),
+            ReadError::UnknownReader(name) => write!(f, "unknown reader: {}", name),
+            ReadError::Failed(e) => write!(f, "{}", e),
+        }
     }
+}
 
-    pub fn stop(&self) {
-        *self.stop_flag.lock().unwrap() = true;
-        if let Some(t) = self.handle.lock().unwrap().take() {
-            let _ = t.join();
+/// Waits up to `timeout` for a card on `reader` (or any reader) and reads the
+/// requested fields. Blocking; call from a blocking thread.
+pub fn read_on_demand(
+    reader: Option<&str>,
+    fields: &[Field],
+    timeout: Duration,
+) -> Result<(String, ThaiIdInfo), ReadError> {
+    let ctx = Context::establish(Scope::User).map_err(|e| ReadError::Failed(e.to_string()))?;
+    let names = match ctx.list_readers_owned() {
+        Ok(names) if !names.is_empty() => names,
+        Ok(_) | Err(Error::NoReadersAvailable) => return Err(ReadError::NoReaders),
+        Err(e) => return Err(ReadError::Failed(e.to_string())),
+    };
+
+    let names: Vec<CString> = match reader {
+        Some(wanted) => {
+            let found: Vec<CString> = names
+                .into_iter()
+                .filter(|n| n.to_string_lossy() == wanted)
+                .collect();
+            if found.is_empty() {
+                return Err(ReadError::UnknownReader(wanted.to_string()));
+            }
+            found
         }
+        None => names,
+    };
+
+    let reader_cstr = wait_for_card(&ctx, names, timeout)?;
+    let name = reader_cstr.to_string_lossy().into_owned();
+    log::write_log_line(&format!("On-demand read on {}", name));
+
+    let card = ctx
+        .connect(&reader_cstr, pcsc::ShareMode::Shared, pcsc::Protocols::ANY)
+        .map_err(|e| ReadError::Failed(format!("Card connect error: {}", e)))?;
+    let _guard = lock_readers();
+    let info = thai_id::read_thai_id(&card, fields).map_err(ReadError::Failed)?;
+    Ok((name, info))
+}
+
+fn wait_for_card(ctx: &Context, names: Vec<CString>, timeout: Duration) -> Result<CString, ReadError> {
+    let deadline = Instant::now() + timeout;
+    let mut states: Vec<pcsc::ReaderState> = names
+        .into_iter()
+        .map(|n| pcsc::ReaderState::new(n, pcsc::State::UNAWARE))
+        .collect();
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match ctx.get_status_change(remaining, &mut states) {
+            Ok(()) => {}
+            Err(Error::Timeout) => return Err(ReadError::Timeout),
+            Err(e) => return Err(ReadError::Failed(e.to_string())),
+        }
+
+        if let Some(rs) = states.iter().find(|rs| {
+            rs.event_state().contains(pcsc::State::PRESENT)
+                && !rs.event_state().contains(pcsc::State::MUTE)
+        }) {
+            return Ok(rs.name().to_owned());
+        }
+
+        if remaining.is_zero() {
+            return Err(ReadError::Timeout);
+        }
+        for rs in &mut states {
+            rs.sync_current_state();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_each_insertion_and_retries_failed_reads() {
+        assert!(should_read(None, 0));
+        assert!(should_read(Some(ReaderState::Empty), 0));
+        assert!(!should_read(Some(ReaderState::Present), 0));
+        assert!(should_read(Some(ReaderState::Error), 1));
+        assert!(should_read(Some(ReaderState::Error), MAX_READ_ATTEMPTS - 1));
+        assert!(!should_read(Some(ReaderState::Error), MAX_READ_ATTEMPTS));
     }
 }
@@ -0,0 +1,323 @@
+use crate::auth::{Auth, Scope};
+use crate::card::{self, CardStore, ReadError, ReaderStatus};
+use crate::error::ApiError;
+use crate::origins::{self, Envelope, OriginRegistry};
+use crate::signing::Signer;
+use crate::thaiid::thai_id::{Field, ThaiIdInfo};
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{DateTime, Local};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use std::time::Duration;
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+
+const DEFAULT_READ_TIMEOUT_MS: u64 = 15_000;
+const MAX_READ_TIMEOUT_MS: u64 = 60_000;
+const DEFAULT_MIN_AGE: i32 = 20;
+
+#[derive(Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PhotoMode {
+    #[default]
+    Link,
+    Inline,
+    None,
+}
+
+#[derive(serde::Deserialize)]
+struct CurrentQuery {
+    reader: Option<String>,
+    #[serde(default)]
+    photo: PhotoMode,
+}
+
+#[derive(serde::Deserialize)]
+struct ReadBody {
+    reader: Option<String>,
+    timeout_ms: Option<u64>,
+    fields: Option<Vec<Field>>,
+}
+
+#[derive(serde::Deserialize)]
+struct AgeCheckQuery {
+    min: Option<i32>,
+    reader: Option<String>,
+    /// Wait for a new card even when one is already present.
+    #[serde(default)]
+    read: bool,
+    timeout_ms: Option<u64>,
+}
+
+/// The only thing an age check releases about the card holder.
+#[derive(serde::Serialize)]
+struct AgeCheck {
+    min_age: i32,
+    of_age: bool,
+    card_expired: bool,
+}
+
+#[derive(serde::Serialize)]
+struct CardPayload {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    th_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    en_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    birth: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gender: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issuer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issue_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    photo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    photo_url: Option<String>,
+}
+
+impl CardPayload {
+    /// Keeps only the selected fields. The photo is left out; callers decide
+    /// between an inline data URL and a link.
+    fn new(info: ThaiIdInfo, fields: &[Field]) -> Self {
+        let pick = |field: Field, value: String| fields.contains(&field).then_some(value);
+        Self {
+            cid: pick(Field::Cid, info.cid),
+            th_name: pick(Field::ThName, info.th_name),
+            en_name: pick(Field::EnName, info.en_name),
+            birth: pick(Field::Birth, info.birth),
+            gender: pick(Field::Gender, info.gender),
+            issuer: pick(Field::Issuer, info.issuer),
+            issue_date: pick(Field::IssueDate, info.issue_date),
+            expire_date: pick(Field::ExpireDate, info.expire_date),
+            address: pick(Field::Address, info.address),
+            photo: None,
+            photo_url: None,
+        }
+    }
+}
+
+fn photo_data_url(photo_base64: &str) -> String {
+    format!("data:image/jpeg;base64,{}", photo_base64)
+}
+
+#[derive(serde::Serialize)]
+struct ReadResult {
+    reader: String,
+    read_at: DateTime<Local>,
+    card: CardPayload,
+}
+
+
+#[derive(serde::Serialize)]
+struct CurrentCard {
+    #[serde(flatten)]
+    status: ReaderStatus,
+    read_at: DateTime<Local>,
+    card: CardPayload,
+}
+
+#[derive(serde::Serialize)]
+struct NoCard {
+    reader: Option<String>,
+    state: &'static str,
+}
+
+/// Card data is sealed for origins that registered a key and signed with
+/// `signer`; see `origins::Envelope`.
+pub fn routes(
+    cards: CardStore,
+    origins: OriginRegistry,
+    signer: Signer,
+    auth: Auth,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let cards_filter = warp::any().map(move || cards.clone());
+    let envelope = origins::envelope(origins, signer);
+    let card_read = auth.require(Scope::CardRead);
+
+    let current = warp::path!("card" / "current")
+        .and(warp::get())
+        .and(card_read.clone())
+        .and(warp::query::<CurrentQuery>())
+        .and(cards_filter.clone())
+        .and(envelope.clone())
+        .and_then(get_current);
+
+    let current_photo = warp::path!("card" / "current" / "photo")
+        .and(warp::get())
+        .and(card_read.clone())
+        .and(warp::query::<CurrentQuery>())
+        .and(cards_filter.clone())
+        .and(envelope.clone())
+        .and_then(get_current_photo);
+
+    let readers = warp::path!("card" / "readers")
+        .and(warp::get())
+        .and(card_read.clone())
+        .and(cards_filter.clone())
+        .and_then(get_readers);
+
+    let read = warp::path!("card" / "read")
+        .and(warp::post())
+        .and(card_read.clone())
+        .and(warp::body::json())
+        .and(envelope.clone())
+        .and_then(post_read);
+
+    let age_check = warp::path!("card" / "age-check")
+        .and(warp::get())
+        .and(auth.require(Scope::CardAge))
+        .and(warp::query::<AgeCheckQuery>())
+        .and(cards_filter)
+        .and(envelope.clone())
+        .and_then(get_age_check);
+
+    current.or(current_photo).or(readers).or(read).or(age_check)
+}
+
+fn lookup(cards: &CardStore, reader: Option<&str>) -> Option<ReaderStatus> {
+    match reader {
+        Some(name) => cards.reader(name),
+        None => cards.current(),
+    }
+}
+
+fn no_card(status: Option<ReaderStatus>) -> warp::reply::Response {
+    let body = match status {
+        Some(s) => warp::reply::json(&s),
+        None => warp::reply::json(&NoCard {
+            reader: None,
+            state: "no_reader",
+        }),
+    };
+    warp::reply::with_status(body, StatusCode::NOT_FOUND).into_response()
+}
+
+async fn get_current(query: CurrentQuery, cards: CardStore, out: Envelope) -> Result<impl Reply, Rejection> {
+    let Some(mut status) = lookup(&cards, query.reader.as_deref()) else {
+        return Ok(no_card(None));
+    };
+    let Some(read) = status.card.take() else {
+        return Ok(no_card(Some(status)));
+    };
+
+    let photo = photo_data_url(&read.info.photo_base64);
+    let mut card = CardPayload::new(read.info, &Field::ALL);
+    match query.photo {
+        PhotoMode::Inline => card.photo = Some(photo),
+        PhotoMode::Link => {
+            card.photo_url = Some(format!(
+                "/api/card/current/photo?reader={}",
+                utf8_percent_encode(&status.reader, NON_ALPHANUMERIC)
+            ))
+        }
+        PhotoMode::None => {}
+    }
+
+    let body = CurrentCard {
+        read_at: read.read_at,
+        card,
+        status,
+    };
+    Ok(out.json(&body))
+}
+
+async fn get_current_photo(
+    query: CurrentQuery,
+    cards: CardStore,
+    out: Envelope,
+) -> Result<impl Reply, Rejection> {
+    let Some(mut status) = lookup(&cards, query.reader.as_deref()) else {
+        return Ok(no_card(None));
+    };
+    let Some(read) = status.card.take() else {
+        return Ok(no_card(Some(status)));
+    };
+
+    let bytes = general_purpose::STANDARD
+        .decode(&read.info.photo_base64)
+        .unwrap_or_default();
+    Ok(out.reply(bytes, "image/jpeg"))
+}
+
+async fn get_readers(cards: CardStore) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&cards.readers()))
+}
+
+fn read_timeout(timeout_ms: Option<u64>) -> Duration {
+    Duration::from_millis(timeout_ms.unwrap_or(DEFAULT_READ_TIMEOUT_MS).min(MAX_READ_TIMEOUT_MS))
+}
+
+/// Waits for a card and reads `fields`, mapping failures to API errors.
+async fn read_now(reader: Option<String>, fields: Vec<Field>, timeout: Duration) -> Result<(String, ThaiIdInfo), ApiError> {
+    let result = tokio::task::spawn_blocking(move || card::read_on_demand(reader.as_deref(), &fields, timeout)).await;
+
+    match result {
+        Ok(Ok(read)) => Ok(read),
+        Ok(Err(e @ ReadError::Timeout)) => Err(ApiError::Timeout(e.to_string())),
+        Ok(Err(e @ ReadError::NoReaders)) => Err(ApiError::Unavailable(e.to_string())),
+        Ok(Err(e @ ReadError::UnknownReader(_))) => Err(ApiError::NotFound(e.to_string())),
+        Ok(Err(e @ ReadError::Failed(_))) => Err(ApiError::BadGateway(e.to_string())),
+        Err(e) => Err(ApiError::Internal(e.to_string())),
+    }
+}
+
+async fn post_read(body: ReadBody, out: Envelope) -> Result<impl Reply, Rejection> {
+    let fields = body.fields.unwrap_or_else(|| Field::ALL.to_vec());
+    let timeout = read_timeout(body.timeout_ms);
+
+    let (reader, info) = read_now(body.reader, fields.clone(), timeout).await?;
+    let photo = fields
+        .contains(&Field::Photo)
+        .then(|| photo_data_url(&info.photo_base64));
+    let mut card = CardPayload::new(info, &fields);
+    card.photo = photo;
+    let body = ReadResult {
+        reader,
+        read_at: Local::now(),
+        card,
+    };
+    Ok(out.json(&body))
+}
+
+async fn get_age_check(
+    query: AgeCheckQuery,
+    cards: CardStore,
+    out: Envelope,
+) -> Result<impl Reply, Rejection> {
+    let min_age = query.min.unwrap_or(DEFAULT_MIN_AGE);
+    if !(0..=150).contains(&min_age) {
+        return Err(ApiError::BadRequest("min must be between 0 and 150".to_string()).into());
+    }
+
+    let current = if query.read {
+        None
+    } else {
+        lookup(&cards, query.reader.as_deref()).and_then(|s| s.card)
+    };
+    let info = match current {
+        Some(read) => read.info,
+        None => {
+            // Only the two fields the answer needs are read from the card.
+            let fields = vec![Field::Birth, Field::ExpireDate];
+            read_now(query.reader, fields, read_timeout(query.timeout_ms)).await?.1
+        }
+    };
+
+    let today = Local::now().date_naive();
+    let birth = info.birth_date().map_err(|e| ApiError::Unprocessable(e.to_string()))?;
+    // An unreadable expiry date is not treated as valid.
+    let card_expired = info.expiry_date().ok().is_none_or(|d| d.is_expired(today));
+
+    let body = AgeCheck {
+        min_age,
+        of_age: birth.age_on(today) >= min_age,
+        card_expired,
+    };
+    Ok(out.json(&body))
+}
@@ -0,0 +1,144 @@
+use crate::auth::{Auth, Scope};
+use crate::events::{Event, EventBus, EventFilter};
+use crate::log;
+use crate::origins::{self, Envelope, OriginRegistry};
+use crate::signing::Signer;
+use futures_util::{SinkExt, StreamExt, future, stream};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use warp::{
+    Filter, Rejection, Reply, sse,
+    ws::{Message, WebSocket, Ws},
+};
+
+#[derive(serde::Deserialize)]
+struct StreamQuery {
+    reader: Option<String>,
+    events: Option<String>,
+    last_event_id: Option<u64>,
+    /// Send each event as a compact JWS instead of plain JSON.
+    #[serde(default)]
+    signed: bool,
+}
+
+fn encode(event: &Event, out: &Envelope, signed: bool) -> Option<String> {
+    let json = serde_json::to_string(event).ok()?;
+    Some(out.message(json, signed))
+}
+
+/// `/ws/card`: pushes card/reader events as JSON text frames. The initial
+/// filter comes from `?reader=a,b&events=read_completed,card_removed`; a client
+/// can replace it at any time by sending `{"readers": [...], "events": [...]}`.
+/// With `?signed=true` each frame is a compact JWS of the event JSON; origins
+/// with a registered key get sealed events.
+pub fn ws_routes(
+    bus: EventBus,
+    origins: OriginRegistry,
+    signer: Signer,
+    auth: Auth,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("ws" / "card")
+        .and(warp::ws())
+        .and(auth.require(Scope::CardRead))
+        .and(warp::query::<StreamQuery>())
+        .and(origins::envelope(origins, signer))
+        .and(warp::any().map(move || bus.clone()))
+        .map(|ws: Ws, query: StreamQuery, out: Envelope, bus: EventBus| {
+            let filter = EventFilter::from_query(query.reader.as_deref(), query.events.as_deref());
+            ws.on_upgrade(move |socket| stream_ws(socket, filter, bus, out, query.signed))
+        })
+}
+
+async fn stream_ws(socket: WebSocket, mut filter: EventFilter, bus: EventBus, out: Envelope, signed: bool) {
+    let (mut tx, mut rx) = socket.split();
+    let mut events = bus.subscribe();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if !filter.matches(&event.event) {
+                        continue;
+                    }
+                    let Some(text) = encode(&event, &out, signed) else { continue };
+                    if tx.send(Message::text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(n)) => {
+                    log::write_log_line(&format!("WebSocket subscriber lagged, {} events dropped", n));
+                }
+                Err(RecvError::Closed) => break,
+            },
+            msg = rx.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    match serde_json::from_str::<EventFilter>(msg.to_str().unwrap_or_default()) {
+                        Ok(f) => filter = f,
+                        Err(e) => {
+                            let err = serde_json::json!({ "type": "error", "error": e.to_string() });
+                            if tx.send(Message::text(err.to_string())).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Some(Ok(msg)) if msg.is_close() => break,
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+}
+
+/// `/api/events`: the same events as a Server-Sent Events stream. Clients that
+/// reconnect with `Last-Event-ID` (or `?last_event_id=`) get the buffered
+/// events they missed before the live feed resumes.
+pub fn sse_routes(
+    bus: EventBus,
+    origins: OriginRegistry,
+    signer: Signer,
+    auth: Auth,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("api" / "events")
+        .and(warp::get())
+        .and(auth.require(Scope::CardRead))
+        .and(warp::query::<StreamQuery>())
+        .and(sse::last_event_id::<u64>())
+        .and(origins::envelope(origins, signer))
+        .and(warp::any().map(move || bus.clone()))
+        .map(|query: StreamQuery, last_event_id: Option<u64>, out: Envelope, bus: EventBus| {
+            let filter = EventFilter::from_query(query.reader.as_deref(), query.events.as_deref());
+            let signed = query.signed;
+            let last_id = last_event_id.or(query.last_event_id);
+            let (backlog, rx) = match last_id {
+                Some(id) => bus.subscribe_after(id),
+                None => (Vec::new(), bus.subscribe()),
+            };
+
+            // A lagging subscriber ends its stream; the browser reconnects with
+            // its Last-Event-ID and catches up from the ring buffer.
+            let live = stream::unfold(rx, |mut rx| async move {
+                match rx.recv().await {
+                    Ok(event) => Some((event, rx)),
+                    Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => None,
+                }
+            });
+
+            let events = stream::iter(backlog)
+                .chain(live)
+                .filter(move |e| future::ready(filter.matches(&e.event)))
+                .filter_map(move |e| {
+                    let event = encode(&e, &out, signed).map(|data| {
+                        Ok::<_, std::convert::Infallible>(
+                            sse::Event::default()
+                                .id(e.id.to_string())
+                                .event(e.event.kind())
+                                .data(data),
+                        )
+                    });
+                    future::ready(event)
+                });
+
+            sse::reply(sse::keep_alive().interval(Duration::from_secs(15)).stream(events))
+        })
+}
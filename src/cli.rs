@@ -0,0 +1,341 @@
+use crate::auth::{self, Scope};
+use crate::crypto::{self, DataCipher, KeySource};
+use crate::server::{ServerConfig, ServerLock};
+use crate::tls;
+use crate::migrator::{self, Migrator, State};
+use crate::products;
+use crate::users::{self, user::Role};
+use chrono::{DateTime, Duration, Local, Utc};
+use sea_orm::{Database, DatabaseConnection};
+use sea_orm_migration::MigratorTrait;
+use std::path::Path;
+
+const TOKENS_USAGE: &str = "usage: server_tray tokens create <name> --scopes a,b [--expires-days n] | list | revoke <id>";
+const DB_USAGE: &str = "usage: server_tray db status | up [n] | down [n] --yes | fresh --yes | seed";
+const USERS_USAGE: &str = "usage: server_tray users create <name> --role cashier|manager|admin | list";
+
+/// Handles `server_tray <command> ...` invocations that run once and exit
+/// instead of starting the tray. Returns `None` when the arguments are not a
+/// command, otherwise the process exit code.
+pub fn run(args: &[String], config: &ServerConfig) -> Option<i32> {
+    let (command, rest) = args.split_first()?;
+    let (db_path, data_key) = (config.db_path.as_str(), &config.data_key);
+    let result = match (command.as_str(), rest.first().map(String::as_str)) {
+        ("db", Some("status")) => db_status(db_path),
+        ("db", Some("up")) => db_up(db_path, &rest[1..]),
+        ("db", Some("down")) => db_down(db_path, &rest[1..]),
+        ("db", Some("fresh")) => db_fresh(db_path, &rest[1..]),
+        ("db", Some("seed")) => db_seed(db_path),
+        ("db", _) => Err(DB_USAGE.to_string()),
+        ("keys", Some("rotate")) => keys_rotate(config),
+        ("keys", Some("reencrypt")) => keys_reencrypt(db_path, data_key),
+        ("keys", _) => Err("usage: server_tray keys rotate|reencrypt".to_string()),
+        ("tls", Some("status")) => tls_status(&config.tls.dir),
+        ("tls", Some("export-ca")) => tls_export_ca(&config.tls.dir, rest.get(1).map(Path::new)),
+        ("tls", _) => Err("usage: server_tray tls status|export-ca [file]".to_string()),
+        ("tokens", Some("create")) => tokens_create(db_path, &rest[1..]),
+        ("tokens", Some("list")) => tokens_list(db_path),
+        ("tokens", Some("revoke")) => tokens_revoke(db_path, rest.get(1).map(String::as_str)),
+        ("tokens", _) => Err(TOKENS_USAGE.to_string()),
+        ("users", Some("create")) => users_create(db_path, &rest[1..]),
+        ("users", Some("list")) => users_list(db_path),
+        ("users", _) => Err(USERS_USAGE.to_string()),
+        _ => return None,
+    };
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            Some(0)
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            Some(1)
+        }
+    }
+}
+
+fn runtime() -> Result<tokio::runtime::Runtime, String> {
+    tokio::runtime::Runtime::new().map_err(|e| e.to_string())
+}
+
+/// Refused while the server is listening: it holds the keys it loaded at
+/// startup and could not read rows re-encrypted under the new one.
+fn keys_rotate(config: &ServerConfig) -> Result<String, String> {
+    if ServerLock::is_held(&config.db_path)? {
+        return Err("the server is running; stop it before rotating the data key".to_string());
+    }
+    let (db_path, data_key) = (config.db_path.as_str(), &config.data_key);
+    let id = DataCipher::rotate(data_key)?;
+    let report = keys_reencrypt(db_path, data_key)?;
+    Ok(format!("New data key {}. {}", id, report))
+}
+
+fn keys_reencrypt(db_path: &str, data_key: &KeySource) -> Result<String, String> {
+    let cipher = DataCipher::load(data_key)?;
+    runtime()?.block_on(async {
+        let db = Database::connect(format!("sqlite://{}", db_path))
+            .await
+            .map_err(|e| e.to_string())?;
+        let report = crypto::reencrypt_rows(&db, &cipher).await.map_err(|e| e.to_string())?;
+        Ok(format!(
+            "Re-encrypted {} card reads, {} webhook deliveries and {} webhook secrets with key {}",
+            report.card_reads,
+            report.deliveries,
+            report.webhook_targets,
+            cipher.current_key_id()
+        ))
+    })
+}
+
+fn tls_status(dir: &Path) -> Result<String, String> {
+    let status = tls::status(dir)?;
+    Ok(format!(
+        "Certificate valid until {}\nCA valid until {}\nCA SHA-256 {}",
+        status.cert_not_after, status.ca_not_after, status.ca_sha256
+    ))
+}
+
+/// Writes the CA certificate to `out`, or returns it for stdout.
+fn tls_export_ca(dir: &Path, out: Option<&Path>) -> Result<String, String> {
+    let pem = tls::ca_pem(dir)?;
+    match out {
+        Some(path) => {
+            std::fs::write(path, pem).map_err(|e| format!("write {}: {}", path.display(), e))?;
+            Ok(format!("CA certificate written to {}", path.display()))
+        }
+        None => Ok(pem.trim_end().to_string()),
+    }
+}
+
+/// Connects to a database that must already exist, for commands that only
+/// look at or remove data.
+async fn existing_db(db_path: &str) -> Result<DatabaseConnection, String> {
+    if !Path::new(db_path).exists() {
+        return Err(format!("No database at {}", db_path));
+    }
+    Database::connect(format!("sqlite://{}", db_path))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// `[n] [--yes]` after `db up`/`down`/`fresh`.
+fn db_args(args: &[String]) -> Result<(Option<u32>, bool), String> {
+    let (mut steps, mut yes) = (None, false);
+    for arg in args {
+        match arg.as_str() {
+            "--yes" => yes = true,
+            n if steps.is_none() => steps = Some(n.parse::<u32>().ok().filter(|n| *n > 0).ok_or(DB_USAGE)?),
+            _ => return Err(DB_USAGE.to_string()),
+        }
+    }
+    Ok((steps, yes))
+}
+
+fn db_status(db_path: &str) -> Result<String, String> {
+    runtime()?.block_on(async {
+        let db = existing_db(db_path).await?;
+        let status = migrator::status(&db).await.map_err(|e| e.to_string())?;
+        let local = |at: &DateTime<Utc>| at.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string();
+        let mut lines = vec![format!("Database {}", db_path)];
+        lines.extend(status.iter().map(|(name, state)| match state {
+            State::Applied(at) => format!("{}	applied {}", name, local(at)),
+            State::Pending => format!("{}	pending", name),
+            State::Unknown(at) => format!("{}	applied {} by a newer version", name, local(at)),
+        }));
+        Ok(lines.join("\n"))
+    })
+}
+
+fn db_up(db_path: &str, args: &[String]) -> Result<String, String> {
+    let (steps, _) = db_args(args)?;
+    runtime()?.block_on(async {
+        let db = Database::connect(format!("sqlite://{}?mode=rwc", db_path))
+            .await
+            .map_err(|e| e.to_string())?;
+        migrator::check_known(&db).await.map_err(|e| e.to_string())?;
+        let mut pending = migrator::names(&db, |s| matches!(s, State::Pending))
+            .await
+            .map_err(|e| e.to_string())?;
+        if pending.is_empty() {
+            return Ok("Database is up to date".to_string());
+        }
+        pending.truncate(steps.map_or(usize::MAX, |n| n as usize));
+        Migrator::up(&db, steps).await.map_err(|e| e.to_string())?;
+        Ok(pending.iter().map(|name| format!("Applied {}", name)).collect::<Vec<_>>().join("\n"))
+    })
+}
+
+/// Rolls back the last `n` (default 1) migrations, dropping what they created.
+fn db_down(db_path: &str, args: &[String]) -> Result<String, String> {
+    let (steps, yes) = db_args(args)?;
+    if !yes {
+        return Err("Rolling back drops tables and columns with their data; add --yes to go ahead".to_string());
+    }
+    let steps = steps.unwrap_or(1);
+    runtime()?.block_on(async {
+        let db = existing_db(db_path).await?;
+        migrator::check_known(&db).await.map_err(|e| e.to_string())?;
+        let applied = migrator::names(&db, |s| matches!(s, State::Applied(_)))
+            .await
+            .map_err(|e| e.to_string())?;
+        if applied.is_empty() {
+            return Ok("No migrations to roll back".to_string());
+        }
+        Migrator::down(&db, Some(steps)).await.map_err(|e| e.to_string())?;
+        Ok(applied
+            .iter()
+            .rev()
+            .take(steps as usize)
+            .map(|name| format!("Rolled back {}", name))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    })
+}
+
+fn db_fresh(db_path: &str, args: &[String]) -> Result<String, String> {
+    let (steps, yes) = db_args(args)?;
+    if steps.is_some() {
+        return Err(DB_USAGE.to_string());
+    }
+    if !yes {
+        return Err("fresh drops every table and all data in it; add --yes to go ahead".to_string());
+    }
+    runtime()?.block_on(async {
+        let db = Database::connect(format!("sqlite://{}?mode=rwc", db_path))
+            .await
+            .map_err(|e| e.to_string())?;
+        Migrator::fresh(&db).await.map_err(|e| e.to_string())?;
+        Ok(format!(
+            "Dropped all tables and applied {} migrations",
+            Migrator::migrations().len()
+        ))
+    })
+}
+
+fn db_seed(db_path: &str) -> Result<String, String> {
+    runtime()?.block_on(async {
+        let db = migrated_db(db_path).await?;
+        let added = products::seed(&db).await.map_err(|e| e.to_string())?;
+        Ok(format!("Added {} sample products", added))
+    })
+}
+
+/// Connects for the token, user and seed commands, bringing the schema up to date
+/// first as the server would.
+async fn migrated_db(db_path: &str) -> Result<DatabaseConnection, String> {
+    let db = Database::connect(format!("sqlite://{}?mode=rwc", db_path))
+        .await
+        .map_err(|e| e.to_string())?;
+    migrator::upgrade(&db).await.map_err(|e| e.to_string())?;
+    Ok(db)
+}
+
+fn tokens_create(db_path: &str, args: &[String]) -> Result<String, String> {
+    let (mut name, mut scopes, mut expires_days) = (None, Vec::new(), None);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scopes" => {
+                let list = args.next().ok_or(TOKENS_USAGE)?;
+                for s in list.split(',') {
+                    scopes.push(Scope::parse(s.trim()).ok_or_else(|| format!("unknown scope {}", s))?);
+                }
+            }
+            "--expires-days" => {
+                let days = args.next().and_then(|d| d.parse::<i64>().ok()).ok_or(TOKENS_USAGE)?;
+                expires_days = Some(days);
+            }
+            _ if name.is_none() && !arg.starts_with("--") => name = Some(arg.as_str()),
+            _ => return Err(TOKENS_USAGE.to_string()),
+        }
+    }
+    let name = name.ok_or(TOKENS_USAGE)?;
+    if scopes.is_empty() {
+        return Err(TOKENS_USAGE.to_string());
+    }
+    let expires_at = expires_days.map(|days| Utc::now() + Duration::days(days));
+
+    runtime()?.block_on(async {
+        let db = migrated_db(db_path).await?;
+        let (row, token) = auth::issue(&db, name, &scopes, expires_at)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(format!(
+            "Token {} ({}) with scopes {}\n{}\nStore it now; it cannot be shown again.",
+            row.id, row.name, row.scopes, token
+        ))
+    })
+}
+
+fn tokens_list(db_path: &str) -> Result<String, String> {
+    runtime()?.block_on(async {
+        let db = migrated_db(db_path).await?;
+        let tokens = auth::list(&db).await.map_err(|e| e.to_string())?;
+        let now = Utc::now();
+        let lines: Vec<String> = tokens
+            .iter()
+            .map(|t| {
+                let state = match (t.revoked_at, t.expires_at) {
+                    (Some(_), _) => "revoked".to_string(),
+                    (None, Some(at)) if at <= now => "expired".to_string(),
+                    (None, Some(at)) => format!("expires {}", at.format("%Y-%m-%d")),
+                    (None, None) => "no expiry".to_string(),
+                };
+                format!("{}\t{}\t{}…\t{}\t{}", t.id, t.name, t.prefix, t.scopes, state)
+            })
+            .collect();
+        Ok(if lines.is_empty() {
+            "No API tokens".to_string()
+        } else {
+            lines.join("\n")
+        })
+    })
+}
+
+fn tokens_revoke(db_path: &str, id: Option<&str>) -> Result<String, String> {
+    let id = id.and_then(|id| id.parse::<i32>().ok()).ok_or(TOKENS_USAGE)?;
+    runtime()?.block_on(async {
+        let db = migrated_db(db_path).await?;
+        match auth::revoke(&db, id).await.map_err(|e| e.to_string())? {
+            true => Ok(format!("Token {} revoked", id)),
+            false => Err(format!("No active token {}", id)),
+        }
+    })
+}
+
+/// The password is read from the first line of stdin so it stays out of the
+/// shell history.
+fn users_create(db_path: &str, args: &[String]) -> Result<String, String> {
+    let (name, role) = match args {
+        [name, flag, role] if flag == "--role" => (name, Role::parse(role).ok_or(USERS_USAGE)?),
+        _ => return Err(USERS_USAGE.to_string()),
+    };
+    eprintln!("Password for {}:", name);
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .map_err(|e| e.to_string())?;
+    let password = password.trim_end_matches(['\r', '\n']);
+
+    runtime()?.block_on(async {
+        let db = migrated_db(db_path).await?;
+        let user = users::create_user(&db, name, password, role).await?;
+        Ok(format!("User {} ({}) created", user.username, user.role.as_str()))
+    })
+}
+
+fn users_list(db_path: &str) -> Result<String, String> {
+    runtime()?.block_on(async {
+        let db = migrated_db(db_path).await?;
+        let list = users::list_users(&db).await.map_err(|e| e.to_string())?;
+        let lines: Vec<String> = list
+            .iter()
+            .map(|u| format!("{}\t{}\t{}", u.id, u.username, u.role.as_str()))
+            .collect();
+        Ok(if lines.is_empty() {
+            "No users".to_string()
+        } else {
+            lines.join("\n")
+        })
+    })
+}
@@ -0,0 +1,396 @@
+use crate::server::card_read;
+use crate::webhook::{delivery, target};
+use aes_gcm::{
+    Aes256Gcm, Key, Nonce,
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+};
+use base64::{Engine as _, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use sea_orm::{ActiveModelTrait, DatabaseConnection, DbErr, EntityTrait, Set};
+use sha2::Sha256;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+// Encrypted values look like `enc:v1:<key id>:<base64 nonce || ciphertext>`.
+// Anything without the prefix is a plaintext value from before encryption was
+// enabled and is returned as is.
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+const KEYRING_SERVICE: &str = "server_tray";
+const KEYRING_USER: &str = "data-key";
+
+/// Where the data keys live: a key file (default `data.key` next to the
+/// executable) or the OS keyring. Both hold the same text format, one
+/// `<id> <base64 key>` per line, oldest first; the last line is the current
+/// key and older keys are kept to decrypt existing rows.
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    File(PathBuf),
+    Keyring,
+}
+
+impl KeySource {
+    pub fn from_env(default_dir: &Path) -> Self {
+        match std::env::var("DATA_KEY_SOURCE").as_deref() {
+            Ok("keyring") => KeySource::Keyring,
+            _ => KeySource::File(
+                std::env::var("DATA_KEY_FILE")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|_| default_dir.join("data.key")),
+            ),
+        }
+    }
+
+    fn read(&self) -> Result<Option<String>, String> {
+        match self {
+            KeySource::File(path) => match fs::read_to_string(path) {
+                Ok(s) => Ok(Some(s)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(format!("read {}: {}", path.display(), e)),
+            },
+            KeySource::Keyring => match keyring_entry()?.get_password() {
+                Ok(s) => Ok(Some(s)),
+                Err(keyring::Error::NoEntry) => Ok(None),
+                Err(e) => Err(format!("keyring: {}", e)),
+            },
+        }
+    }
+
+    fn write(&self, contents: &str) -> Result<(), String> {
+        match self {
+            KeySource::File(path) => write_private(path, contents)
+                .map_err(|e| format!("write {}: {}", path.display(), e)),
+            KeySource::Keyring => keyring_entry()?
+                .set_password(contents)
+                .map_err(|e| format!("keyring: {}", e)),
+        }
+    }
+}
+
+fn keyring_entry() -> Result<keyring::Entry, String> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(|e| format!("keyring: {}", e))
+}
+
+pub fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents.as_bytes())
+}
+
+struct Keys {
+    ids: Vec<String>,
+    ciphers: Vec<Aes256Gcm>,
+    index_key: Vec<u8>,
+    log_key: Vec<u8>,
+}
+
+/// AES-256-GCM field encryption for the personal data columns.
+#[derive(Clone)]
+pub struct DataCipher {
+    keys: Arc<Keys>,
+}
+
+impl DataCipher {
+    /// Loads the keys, generating the first one on first run.
+    pub fn load(source: &KeySource) -> Result<Self, String> {
+        let contents = match source.read()? {
+            Some(contents) => contents,
+            None => {
+                let contents = key_line("k1");
+                source.write(&contents)?;
+                contents
+            }
+        };
+        Self::parse(&contents)
+    }
+
+    /// Adds a new current key and returns its id. Existing rows stay readable;
+    /// run `reencrypt_rows` to move them to the new key.
+    pub fn rotate(source: &KeySource) -> Result<String, String> {
+        let mut contents = source.read()?.unwrap_or_default();
+        let current = Self::parse(&contents).ok();
+        let next = current.map(|c| c.keys.ids.len() + 1).unwrap_or(1);
+        let id = format!("k{}", next);
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+        contents.push_str(&key_line(&id));
+        source.write(&contents)?;
+        Ok(id)
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut ids = Vec::new();
+        let mut ciphers = Vec::new();
+        let mut index_key = None;
+        let mut log_key = None;
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            let (id, b64) = line.split_once(' ').ok_or("malformed key line")?;
+            let bytes = general_purpose::STANDARD
+                .decode(b64.trim())
+                .map_err(|e| format!("key {}: {}", id, e))?;
+            if bytes.len() != 32 {
+                return Err(format!("key {}: expected 32 bytes", id));
+            }
+            // The CID lookup and log digests must not change on rotation, so
+            // they are derived from the first key, which is never removed.
+            index_key.get_or_insert_with(|| derive(&bytes, b"card_reads.cid"));
+            log_key.get_or_insert_with(|| derive(&bytes, b"server_tray.log"));
+            ids.push(id.to_string());
+            ciphers.push(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&bytes)));
+        }
+        let (Some(index_key), Some(log_key)) = (index_key, log_key) else {
+            return Err("no data keys".to_string());
+        };
+        Ok(Self {
+            keys: Arc::new(Keys {
+                ids,
+                ciphers,
+                index_key,
+                log_key,
+            }),
+        })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        self.keys.ids.last().map(String::as_str).unwrap_or_default()
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let cipher = self.keys.ciphers.last().expect("at least one key");
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("AES-GCM encryption does not fail for in-memory buffers");
+        let mut blob = nonce.to_vec();
+        blob.extend_from_slice(&ciphertext);
+        format!("{}{}:{}", PREFIX, self.current_key_id(), general_purpose::STANDARD.encode(blob))
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String, String> {
+        let Some(rest) = value.strip_prefix(PREFIX) else {
+            return Ok(value.to_string());
+        };
+        let (id, b64) = rest.split_once(':').ok_or("malformed ciphertext")?;
+        let index = self
+            .keys
+            .ids
+            .iter()
+            .position(|k| k == id)
+            .ok_or_else(|| format!("unknown data key {}", id))?;
+        let blob = general_purpose::STANDARD
+            .decode(b64)
+            .map_err(|e| e.to_string())?;
+        if blob.len() < NONCE_LEN {
+            return Err("malformed ciphertext".to_string());
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+        let plaintext = self.keys.ciphers[index]
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| format!("decryption failed with key {}", id))?;
+        String::from_utf8(plaintext).map_err(|e| e.to_string())
+    }
+
+    pub fn encrypt_opt(&self, value: Option<&str>) -> Option<String> {
+        value.map(|v| self.encrypt(v))
+    }
+
+    pub fn decrypt_opt(&self, value: Option<&str>) -> Result<Option<String>, String> {
+        value.map(|v| self.decrypt(v)).transpose()
+    }
+
+    /// True when the value is already encrypted with the current key.
+    pub fn is_current(&self, value: &str) -> bool {
+        value
+            .strip_prefix(PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .is_some_and(|(id, _)| id == self.current_key_id())
+    }
+
+    /// Keyed digest used to look up rows by CID without storing it in clear.
+    pub fn cid_digest(&self, cid: &str) -> String {
+        let mut mac = hmac(&self.keys.index_key);
+        mac.update(cid.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Key for the digests `LOG_REDACT_*=hash` writes to the log.
+    pub fn log_key(&self) -> Vec<u8> {
+        self.keys.log_key.clone()
+    }
+}
+
+fn derive(key: &[u8], label: &[u8]) -> Vec<u8> {
+    let mut mac = hmac(key);
+    mac.update(label);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// `KeyInit` is in scope for AES, so `Mac` has to be named explicitly.
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length")
+}
+
+fn key_line(id: &str) -> String {
+    let key = Aes256Gcm::generate_key(&mut OsRng);
+    format!("{} {}\n", id, general_purpose::STANDARD.encode(key))
+}
+
+#[derive(Debug, Default)]
+pub struct ReencryptReport {
+    pub card_reads: u64,
+    pub deliveries: u64,
+    pub webhook_targets: u64,
+}
+
+/// Re-encrypts every stored value that is plaintext or uses an old key.
+pub async fn reencrypt_rows(db: &DatabaseConnection, cipher: &DataCipher) -> Result<ReencryptReport, DbErr> {
+    let mut report = ReencryptReport::default();
+    let stale = |v: &Option<String>| v.as_deref().is_some_and(|v| !cipher.is_current(v));
+    let reseal = |v: Option<String>| -> Result<Option<String>, DbErr> {
+        let plain = cipher.decrypt_opt(v.as_deref()).map_err(DbErr::Custom)?;
+        Ok(cipher.encrypt_opt(plain.as_deref()))
+    };
+
+    for row in card_read::Entity::find().all(db).await? {
+        if !(stale(&row.cid) || stale(&row.th_name) || stale(&row.en_name)) {
+            continue;
+        }
+        let plain_cid = cipher.decrypt_opt(row.cid.as_deref()).map_err(DbErr::Custom)?;
+        let (cid, th_name, en_name) = (row.cid.clone(), row.th_name.clone(), row.en_name.clone());
+        let mut row: card_read::ActiveModel = row.into();
+        row.cid = Set(reseal(cid)?);
+        row.cid_digest = Set(plain_cid.as_deref().map(|c| cipher.cid_digest(c)));
+        row.th_name = Set(reseal(th_name)?);
+        row.en_name = Set(reseal(en_name)?);
+        row.update(db).await?;
+        report.card_reads += 1;
+    }
+
+    for row in delivery::Entity::find().all(db).await? {
+        if cipher.is_current(&row.payload) {
+            continue;
+        }
+        let plain = cipher.decrypt(&row.payload).map_err(DbErr::Custom)?;
+        let mut row: delivery::ActiveModel = row.into();
+        row.payload = Set(cipher.encrypt(&plain));
+        row.update(db).await?;
+        report.deliveries += 1;
+    }
+
+    for row in target::Entity::find().all(db).await? {
+        if cipher.is_current(&row.secret) {
+            continue;
+        }
+        let plain = cipher.decrypt(&row.secret).map_err(DbErr::Custom)?;
+        let mut row: target::ActiveModel = row.into();
+        row.secret = Set(cipher.encrypt(&plain));
+        row.update(db).await?;
+        report.webhook_targets += 1;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_file(name: &str) -> KeySource {
+        let path = std::env::temp_dir().join(format!("server_tray_{}_{}.key", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        KeySource::File(path)
+    }
+
+    fn remove(source: KeySource) {
+        if let KeySource::File(path) = source {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn round_trips_and_passes_plaintext_through() {
+        let source = key_file("round_trip");
+        let cipher = DataCipher::load(&source).unwrap();
+        assert_eq!(cipher.current_key_id(), "k1");
+
+        let stored = cipher.encrypt("1101700203451");
+        assert!(stored.starts_with("enc:v1:k1:"), "{}", stored);
+        assert_ne!(stored, cipher.encrypt("1101700203451"));
+        assert_eq!(cipher.decrypt(&stored).unwrap(), "1101700203451");
+        assert_eq!(cipher.decrypt("written before encryption").unwrap(), "written before encryption");
+
+        let (prefix, blob) = stored.rsplit_once(':').unwrap();
+        let mut bytes = general_purpose::STANDARD.decode(blob).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        let tampered = format!("{}:{}", prefix, general_purpose::STANDARD.encode(bytes));
+        assert!(cipher.decrypt(&tampered).is_err());
+
+        // Loading again reads the key written on first run
+        let again = DataCipher::load(&source).unwrap();
+        assert_eq!(again.decrypt(&stored).unwrap(), "1101700203451");
+        remove(source);
+    }
+
+    #[test]
+    fn rotation_keeps_old_rows_readable_and_digests_stable() {
+        let source = key_file("rotate");
+        let before = DataCipher::load(&source).unwrap();
+        let old = before.encrypt("Somchai");
+
+        assert_eq!(DataCipher::rotate(&source).unwrap(), "k2");
+        let after = DataCipher::load(&source).unwrap();
+        assert_eq!(after.current_key_id(), "k2");
+        assert_eq!(after.decrypt(&old).unwrap(), "Somchai");
+        assert!(!after.is_current(&old));
+        assert!(after.is_current(&after.encrypt("Somchai")));
+        assert_eq!(after.cid_digest("1101700203451"), before.cid_digest("1101700203451"));
+        assert_eq!(after.log_key(), before.log_key());
+
+        // A cipher that predates the rotation cannot read the new key's rows
+        assert_eq!(
+            before.decrypt(&after.encrypt("Somchai")),
+            Err("unknown data key k2".to_string())
+        );
+        remove(source);
+    }
+
+    #[tokio::test]
+    async fn reencrypts_plaintext_webhook_secrets() {
+        use chrono::Utc;
+        use sea_orm::ActiveModelTrait;
+
+        let source = key_file("webhook_secrets");
+        let cipher = DataCipher::load(&source).unwrap();
+        let db = crate::migrator::test_db().await;
+        let row = target::ActiveModel {
+            url: Set("https://example.com/hook".to_string()),
+            secret: Set("written before encryption".to_string()),
+            events: Set(String::new()),
+            readers: Set(String::new()),
+            enabled: Set(true),
+            created_at: Set(Utc::now()),
+            ..Default::default()
+        }
+        .insert(&db)
+        .await
+        .unwrap();
+
+        let report = reencrypt_rows(&db, &cipher).await.unwrap();
+        assert_eq!(report.webhook_targets, 1);
+        let stored = target::Entity::find_by_id(row.id).one(&db).await.unwrap().unwrap();
+        assert!(cipher.is_current(&stored.secret));
+        assert_eq!(cipher.decrypt(&stored.secret).unwrap(), "written before encryption");
+        remove(source);
+    }
+}
@@ -0,0 +1,196 @@
+use crate::log;
+use sea_orm::{DbErr, SqlErr};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use warp::{
+    Rejection, Reply,
+    http::{HeaderValue, StatusCode, Uri, header},
+};
+
+/// Messages per request field, e.g. `{"name": ["must not be empty"]}`.
+pub type FieldErrors = BTreeMap<String, Vec<String>>;
+
+/// Why a request failed. Handlers reject with it and `recover` turns it into
+/// an RFC 7807 `application/problem+json` reply.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    /// Answered with `WWW-Authenticate: Bearer`.
+    Unauthorized(String),
+    /// For HTML pages: answered with a redirect to the login page.
+    LoginRequired,
+    Forbidden(String),
+    NotFound(String),
+    /// No card arrived in time.
+    Timeout(String),
+    Conflict(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    /// The body parsed but broke the rules for its fields; answered with 422
+    /// and the messages under `errors`.
+    Invalid(FieldErrors),
+    /// The request was understood but its subject cannot be used, e.g. a
+    /// card with an unreadable birth date.
+    Unprocessable(String),
+    /// The card or reader failed.
+    BadGateway(String),
+    /// The database is busy or locked; worth retrying.
+    Unavailable(String),
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::LoginRequired => StatusCode::SEE_OTHER,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::Invalid(_) | ApiError::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn detail(&self) -> &str {
+        match self {
+            ApiError::BadRequest(d)
+            | ApiError::Unauthorized(d)
+            | ApiError::Forbidden(d)
+            | ApiError::NotFound(d)
+            | ApiError::Timeout(d)
+            | ApiError::Conflict(d)
+            | ApiError::PayloadTooLarge(d)
+            | ApiError::UnsupportedMediaType(d)
+            | ApiError::Unprocessable(d)
+            | ApiError::BadGateway(d)
+            | ApiError::Unavailable(d)
+            | ApiError::Internal(d) => d,
+            ApiError::LoginRequired => "sign in required",
+            ApiError::Invalid(_) => "request has invalid fields",
+        }
+    }
+
+    pub fn to_response(&self) -> warp::reply::Response {
+        match self {
+            ApiError::LoginRequired => warp::redirect::see_other(Uri::from_static("/login")).into_response(),
+            ApiError::Unauthorized(detail) => {
+                let mut res = problem(StatusCode::UNAUTHORIZED, detail);
+                res.headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                res
+            }
+            ApiError::Invalid(errors) => problem_with(self.status(), self.detail(), Some(errors)),
+            error => problem(error.status(), error.detail()),
+        }
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl From<validator::ValidationErrors> for ApiError {
+    fn from(e: validator::ValidationErrors) -> Self {
+        let errors = e
+            .field_errors()
+            .into_iter()
+            .map(|(field, errors)| {
+                let messages = errors
+                    .iter()
+                    .map(|e| match &e.message {
+                        Some(message) => message.to_string(),
+                        None => e.code.to_string(),
+                    })
+                    .collect();
+                (field.to_string(), messages)
+            })
+            .collect();
+        ApiError::Invalid(errors)
+    }
+}
+
+impl From<DbErr> for ApiError {
+    fn from(e: DbErr) -> Self {
+        if let Some(SqlErr::UniqueConstraintViolation(detail)) = e.sql_err() {
+            return ApiError::Conflict(detail);
+        }
+        let message = e.to_string();
+        // SQLITE_BUSY / SQLITE_LOCKED
+        if message.contains("database is locked") || message.contains("database table is locked") {
+            return ApiError::Unavailable("database is busy, try again".to_string());
+        }
+        log::write_log_line(&format!("Database error: {}", message));
+        ApiError::Internal("database error".to_string())
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'a str,
+    status: u16,
+    detail: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a FieldErrors>,
+}
+
+/// An `application/problem+json` reply with `detail` as the message.
+pub fn problem(status: StatusCode, detail: impl AsRef<str>) -> warp::reply::Response {
+    problem_with(status, detail.as_ref(), None)
+}
+
+fn problem_with(status: StatusCode, detail: &str, errors: Option<&FieldErrors>) -> warp::reply::Response {
+    let body = Problem {
+        kind: "about:blank",
+        title: status.canonical_reason().unwrap_or_default(),
+        status: status.as_u16(),
+        detail,
+        errors,
+    };
+    let mut res = warp::reply::with_status(warp::reply::json(&body), status).into_response();
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/problem+json"),
+    );
+    res
+}
+
+/// The one rejection handler: `ApiError`s and warp's own rejections become
+/// problem replies.
+pub async fn recover(rejection: Rejection) -> Result<warp::reply::Response, Infallible> {
+    if let Some(error) = rejection.find::<ApiError>() {
+        return Ok(error.to_response());
+    }
+
+    let (status, detail) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, format!("malformed JSON body: {}", e))
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MissingHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::MissingCookie>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if rejection.find::<warp::reject::LengthRequired>().is_some() {
+        (StatusCode::LENGTH_REQUIRED, "Content-Length required".to_string())
+    } else if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        (StatusCode::PAYLOAD_TOO_LARGE, "request body too large".to_string())
+    } else if let Some(e) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "method not allowed".to_string())
+    } else {
+        log::write_log_line(&format!("Unhandled rejection: {:?}", rejection));
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal error".to_string())
+    };
+    Ok(problem(status, detail))
+}
@@ -0,0 +1,223 @@
+use crate::thaiid::thai_id::ThaiIdInfo;
+use chrono::{DateTime, Local};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+const CHANNEL_CAPACITY: usize = 64;
+const HISTORY_CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CardEvent {
+    ReaderAttached { reader: String },
+    ReaderDetached { reader: String },
+    CardInserted { reader: String },
+    ReadStarted { reader: String },
+    ReadCompleted { reader: String, card: Box<ThaiIdInfo> },
+    ReadFailed { reader: String, error: String },
+    CardRemoved { reader: String },
+}
+
+impl CardEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CardEvent::ReaderAttached { .. } => "reader_attached",
+            CardEvent::ReaderDetached { .. } => "reader_detached",
+            CardEvent::CardInserted { .. } => "card_inserted",
+            CardEvent::ReadStarted { .. } => "read_started",
+            CardEvent::ReadCompleted { .. } => "read_completed",
+            CardEvent::ReadFailed { .. } => "read_failed",
+            CardEvent::CardRemoved { .. } => "card_removed",
+        }
+    }
+
+    pub fn reader(&self) -> &str {
+        match self {
+            CardEvent::ReaderAttached { reader }
+            | CardEvent::ReaderDetached { reader }
+            | CardEvent::CardInserted { reader }
+            | CardEvent::ReadStarted { reader }
+            | CardEvent::ReadCompleted { reader, .. }
+            | CardEvent::ReadFailed { reader, .. }
+            | CardEvent::CardRemoved { reader } => reader,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Event {
+    pub id: u64,
+    pub at: DateTime<Local>,
+    #[serde(flatten)]
+    pub event: CardEvent,
+}
+
+struct History {
+    next_id: u64,
+    events: VecDeque<Arc<Event>>,
+}
+
+/// Fan-out of card/reader events from the listener thread to any number of
+/// async subscribers. Publishing never blocks; slow subscribers lag. The last
+/// `HISTORY_CAPACITY` events are kept so reconnecting clients can resume.
+#[derive(Clone)]
+pub struct EventBus {
+    tx: broadcast::Sender<Arc<Event>>,
+    history: Arc<Mutex<History>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self {
+            tx,
+            history: Arc::new(Mutex::new(History {
+                // Seeded from the clock so ids keep increasing across restarts
+                // and a stale Last-Event-ID never hides new events.
+                next_id: Local::now().timestamp_millis() as u64,
+                events: VecDeque::with_capacity(HISTORY_CAPACITY),
+            })),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn publish(&self, event: CardEvent) {
+        // Hold the history lock while sending so that `subscribe_after` sees
+        // every event either in the backlog or on the channel, never both.
+        let mut history = self.history.lock().unwrap();
+        let event = Arc::new(Event {
+            id: history.next_id,
+            at: Local::now(),
+            event,
+        });
+        history.next_id += 1;
+        if history.events.len() == HISTORY_CAPACITY {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // No subscribers is not an error.
+        let _ = self.tx.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Event>> {
+        self.tx.subscribe()
+    }
+
+    /// Removes buffered reads of `cid` so they cannot be replayed.
+    pub fn forget_cid(&self, cid: &str) -> usize {
+        let mut history = self.history.lock().unwrap();
+        let before = history.events.len();
+        history.events.retain(|e| {
+            !matches!(&e.event, CardEvent::ReadCompleted { card, .. } if card.cid == cid)
+        });
+        before - history.events.len()
+    }
+
+    /// Subscribes and returns the buffered events newer than `last_id`.
+    pub fn subscribe_after(&self, last_id: u64) -> (Vec<Arc<Event>>, broadcast::Receiver<Arc<Event>>) {
+        let history = self.history.lock().unwrap();
+        let backlog = history
+            .events
+            .iter()
+            .filter(|e| e.id > last_id)
+            .cloned()
+            .collect();
+        (backlog, self.tx.subscribe())
+    }
+}
+
+/// Reader and event-type selection shared by the streaming endpoints. Empty
+/// lists match everything.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct EventFilter {
+    #[serde(default)]
+    pub readers: Vec<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+impl EventFilter {
+    /// Builds a filter from comma-separated query values.
+    pub fn from_query(readers: Option<&str>, events: Option<&str>) -> Self {
+        let split = |s: Option<&str>| {
+            s.map(|s| {
+                s.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+        };
+        Self {
+            readers: split(readers),
+            events: split(events),
+        }
+    }
+
+    pub fn matches(&self, event: &CardEvent) -> bool {
+        (self.readers.is_empty() || self.readers.iter().any(|r| r == event.reader()))
+            && (self.events.is_empty() || self.events.iter().any(|e| e == event.kind()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inserted(reader: &str) -> CardEvent {
+        CardEvent::CardInserted { reader: reader.to_string() }
+    }
+
+    #[test]
+    fn resumes_after_the_last_seen_id_without_repeats() {
+        let bus = EventBus::new();
+        bus.publish(inserted("a"));
+        bus.publish(inserted("b"));
+        bus.publish(inserted("c"));
+        let (all, _) = bus.subscribe_after(0);
+        assert_eq!(all.len(), 3);
+        assert!(all.windows(2).all(|w| w[1].id == w[0].id + 1));
+
+        let (backlog, mut live) = bus.subscribe_after(all[0].id);
+        let readers: Vec<_> = backlog.iter().map(|e| e.event.reader()).collect();
+        assert_eq!(readers, ["b", "c"]);
+        assert!(live.try_recv().is_err());
+
+        bus.publish(inserted("d"));
+        let next = live.try_recv().unwrap();
+        assert_eq!(next.event.reader(), "d");
+        assert_eq!(next.id, all[2].id + 1);
+    }
+
+    #[test]
+    fn keeps_only_the_latest_history() {
+        let bus = EventBus::new();
+        for _ in 0..HISTORY_CAPACITY + 10 {
+            bus.publish(inserted("a"));
+        }
+        let (backlog, _) = bus.subscribe_after(0);
+        assert_eq!(backlog.len(), HISTORY_CAPACITY);
+        let (newer, _) = bus.subscribe_after(backlog[HISTORY_CAPACITY - 2].id);
+        assert_eq!(newer.len(), 1);
+    }
+
+    #[test]
+    fn filters_by_reader_and_kind() {
+        let everything = EventFilter::from_query(None, Some(" , "));
+        assert!(everything.matches(&inserted("a")));
+
+        let filter = EventFilter::from_query(Some("a, b"), Some("card_inserted"));
+        assert!(filter.matches(&inserted("b")));
+        assert!(!filter.matches(&inserted("c")));
+        assert!(!filter.matches(&CardEvent::CardRemoved { reader: "a".to_string() }));
+    }
+}
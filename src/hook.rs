@@ -0,0 +1,282 @@
+use crate::events::{CardEvent, Event, EventBus, EventFilter};
+use crate::log;
+use std::{
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, process::Command, sync::broadcast::error::RecvError};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookInput {
+    Stdin,
+    File,
+}
+
+/// External program started for each matching card event. Disabled unless
+/// `CARD_HOOK_CMD` is set.
+#[derive(Clone, Debug)]
+pub struct HookConfig {
+    pub program: PathBuf,
+    pub args: Vec<String>,
+    pub input: HookInput,
+    pub timeout: Duration,
+    pub filter: EventFilter,
+}
+
+impl HookConfig {
+    pub fn from_env() -> Option<Self> {
+        let program = std::env::var("CARD_HOOK_CMD").ok().filter(|s| !s.is_empty())?;
+        let args = std::env::var("CARD_HOOK_ARGS")
+            .map(|s| s.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        let input = match std::env::var("CARD_HOOK_INPUT").as_deref() {
+            Ok("file") => HookInput::File,
+            _ => HookInput::Stdin,
+        };
+        let timeout = std::env::var("CARD_HOOK_TIMEOUT_SECS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::from_secs(30));
+        let events = std::env::var("CARD_HOOK_EVENTS").unwrap_or_else(|_| "read_completed".to_string());
+
+        Some(Self {
+            program: PathBuf::from(program),
+            args,
+            input,
+            timeout,
+            filter: EventFilter::from_query(None, Some(&events)),
+        })
+    }
+}
+
+/// Runs the hook for each matching event, one at a time and in order.
+pub fn spawn(config: HookConfig, bus: EventBus) {
+    log::write_log_line(&format!("Card hook enabled: {}", config.program.display()));
+    tokio::spawn(async move {
+        let mut rx = bus.subscribe();
+        loop {
+            match rx.recv().await {
+                Ok(event) if config.filter.matches(&event.event) => run(&config, &event).await,
+                Ok(_) => {}
+                Err(RecvError::Lagged(n)) => {
+                    log::write_log_line(&format!("Card hook lagged, {} events skipped", n));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+async fn run(config: &HookConfig, event: &Event) {
+    let json = match serde_json::to_vec(event) {
+        Ok(json) => json,
+        Err(e) => {
+            log::write_log_line(&format!("Card hook payload failed: {}", e));
+            return;
+        }
+    };
+
+    let mut cmd = Command::new(&config.program);
+    cmd.args(&config.args)
+        .envs(env_vars(event))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true);
+
+    let temp_file = match config.input {
+        HookInput::Stdin => {
+            cmd.stdin(Stdio::piped());
+            None
+        }
+        HookInput::File => {
+            let path = std::env::temp_dir().join(format!("server_tray_card_{}.json", event.id));
+            if let Err(e) = write_private(&path, &json) {
+                log::write_log_line(&format!("Card hook temp file failed: {}", e));
+                return;
+            }
+            cmd.stdin(Stdio::null()).env("CARD_JSON_FILE", &path);
+            Some(path)
+        }
+    };
+
+    let started = Instant::now();
+    match cmd.spawn() {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                // A hook that ignores stdin may close it early; not an error.
+                let _ = stdin.write_all(&json).await;
+            }
+            match tokio::time::timeout(config.timeout, child.wait()).await {
+                Ok(Ok(status)) => log::write_log_line(&format!(
+                    "Card hook {} for event {} exited with {} after {}ms",
+                    config.program.display(),
+                    event.id,
+                    status,
+                    started.elapsed().as_millis()
+                )),
+                Ok(Err(e)) => log::write_log_line(&format!("Card hook wait failed: {}", e)),
+                Err(_) => {
+                    let _ = child.kill().await;
+                    log::write_log_line(&format!(
+                        "Card hook {} for event {} killed after {}s timeout",
+                        config.program.display(),
+                        event.id,
+                        config.timeout.as_secs()
+                    ));
+                }
+            }
+        }
+        Err(e) => log::write_log_line(&format!(
+            "Card hook {} failed to start: {}",
+            config.program.display(),
+            e
+        )),
+    }
+
+    if let Some(path) = temp_file {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+fn env_vars(event: &Event) -> Vec<(&'static str, String)> {
+    let mut vars = vec![
+        ("CARD_EVENT", event.event.kind().to_string()),
+        ("CARD_EVENT_ID", event.id.to_string()),
+        ("CARD_READER", event.event.reader().to_string()),
+    ];
+    match &event.event {
+        CardEvent::ReadCompleted { card, .. } => {
+            vars.push(("CARD_CID", card.cid.clone()));
+            vars.push(("CARD_TH_NAME", card.th_name.clone()));
+            vars.push(("CARD_EN_NAME", card.en_name.clone()));
+            vars.push(("CARD_BIRTH", card.birth.clone()));
+            vars.push(("CARD_GENDER", card.gender.clone()));
+            vars.push(("CARD_EXPIRE_DATE", card.expire_date.clone()));
+        }
+        CardEvent::ReadFailed { error, .. } => vars.push(("CARD_ERROR", error.clone())),
+        _ => {}
+    }
+    vars
+}
+
+fn write_private(path: &PathBuf, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::thaiid::thai_id::ThaiIdInfo;
+    use chrono::Local;
+    use std::fs;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("server_tray_hook_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn shell(script: String, input: HookInput, timeout: Duration) -> HookConfig {
+        HookConfig {
+            program: PathBuf::from("sh"),
+            args: vec!["-c".to_string(), script],
+            input,
+            timeout,
+            filter: EventFilter::from_query(None, None),
+        }
+    }
+
+    fn read_completed(id: u64) -> Event {
+        let card = ThaiIdInfo {
+            cid: "1101700203451".to_string(),
+            th_name: "นาย สมชาย ใจดี".to_string(),
+            en_name: "Mr. Somchai Jaidee".to_string(),
+            birth: "25300101".to_string(),
+            gender: "1".to_string(),
+            issuer: String::new(),
+            issue_date: String::new(),
+            expire_date: "25700101".to_string(),
+            address: String::new(),
+            photo_base64: String::new(),
+        };
+        Event {
+            id,
+            at: Local::now(),
+            event: CardEvent::ReadCompleted { reader: "Reader 0".to_string(), card: Box::new(card) },
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_the_event_on_stdin_and_in_card_variables() {
+        let dir = scratch("stdin");
+        let script = format!(
+            "env | grep '^CARD_' > '{0}/env'; cat > '{0}/event.json'",
+            dir.display()
+        );
+        run(&shell(script, HookInput::Stdin, Duration::from_secs(10)), &read_completed(7)).await;
+
+        let env = fs::read_to_string(dir.join("env")).unwrap();
+        for var in [
+            "CARD_EVENT=read_completed",
+            "CARD_EVENT_ID=7",
+            "CARD_READER=Reader 0",
+            "CARD_CID=1101700203451",
+            "CARD_EN_NAME=Mr. Somchai Jaidee",
+            "CARD_EXPIRE_DATE=25700101",
+        ] {
+            assert!(env.lines().any(|l| l == var), "{} missing from {}", var, env);
+        }
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("event.json")).unwrap()).unwrap();
+        assert_eq!(json["id"], 7);
+        assert_eq!(json["card"]["cid"], "1101700203451");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn passes_a_private_temp_file_and_removes_it_afterwards() {
+        let dir = scratch("file");
+        let script = format!(
+            "echo \"$CARD_JSON_FILE\" > '{0}/path'; stat -c %a \"$CARD_JSON_FILE\" > '{0}/mode'; cp \"$CARD_JSON_FILE\" '{0}/event.json'",
+            dir.display()
+        );
+        run(&shell(script, HookInput::File, Duration::from_secs(10)), &read_completed(8)).await;
+
+        let path = fs::read_to_string(dir.join("path")).unwrap();
+        assert!(path.trim().ends_with("server_tray_card_8.json"), "{}", path);
+        assert!(!std::path::Path::new(path.trim()).exists());
+        assert_eq!(fs::read_to_string(dir.join("mode")).unwrap().trim(), "600");
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(dir.join("event.json")).unwrap()).unwrap();
+        assert_eq!(json["id"], 8);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kills_a_hook_that_outlives_its_timeout() {
+        let dir = scratch("timeout");
+        let script = format!("echo $$ > '{}/pid'; exec sleep 30", dir.display());
+        let started = Instant::now();
+        run(&shell(script, HookInput::Stdin, Duration::from_secs(1)), &read_completed(9)).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        let pid = fs::read_to_string(dir.join("pid")).unwrap();
+        let alive = std::process::Command::new("kill")
+            .args(["-0", pid.trim()])
+            .stderr(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(!alive.success(), "hook {} still running", pid.trim());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
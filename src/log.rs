@@ -1,16 +1,358 @@
-use std::{fs::OpenOptions, io::Write, path::PathBuf};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
 
-pub fn write_log_line(message: &str) {
+// Appends and rewrites (retention, erasure) must not interleave.
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+static REDACTION: OnceLock<RedactionPolicy> = OnceLock::new();
+static HASH_KEY: OnceLock<Vec<u8>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldRule {
+    Full,
+    Mask,
+    Hash,
+    Omit,
+}
+
+impl FieldRule {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "full" => Some(FieldRule::Full),
+            "mask" => Some(FieldRule::Mask),
+            "hash" => Some(FieldRule::Hash),
+            "omit" => Some(FieldRule::Omit),
+            _ => None,
+        }
+    }
+}
+
+/// How card data is written to `server_tray.log`. `details` covers birth date,
+/// gender, issuer, issue/expiry dates and address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RedactionPolicy {
+    pub cid: FieldRule,
+    pub names: FieldRule,
+    pub details: FieldRule,
+    pub photo: bool,
+}
+
+impl RedactionPolicy {
+    pub const OFF: Self = Self {
+        cid: FieldRule::Full,
+        names: FieldRule::Full,
+        details: FieldRule::Full,
+        photo: true,
+    };
+
+    pub const MASK: Self = Self {
+        cid: FieldRule::Mask,
+        names: FieldRule::Hash,
+        details: FieldRule::Omit,
+        photo: false,
+    };
+
+    pub const STRICT: Self = Self {
+        cid: FieldRule::Hash,
+        names: FieldRule::Omit,
+        details: FieldRule::Omit,
+        photo: false,
+    };
+
+    /// `LOG_REDACTION=off|mask|strict` picks a preset (`mask` in release
+    /// builds, `off` in debug builds); `LOG_REDACT_CID`, `LOG_REDACT_NAMES`
+    /// and `LOG_REDACT_DETAILS` (`full|mask|hash|omit`) and `LOG_PHOTO`
+    /// (`true|false`) override single fields.
+    pub fn from_env() -> Self {
+        let mut policy = match std::env::var("LOG_REDACTION").as_deref() {
+            Ok("off") => Self::OFF,
+            Ok("mask") => Self::MASK,
+            Ok("strict") => Self::STRICT,
+            _ if cfg!(debug_assertions) => Self::OFF,
+            _ => Self::MASK,
+        };
+        let rule = |key: &str| std::env::var(key).ok().and_then(|s| FieldRule::parse(&s));
+        if let Some(r) = rule("LOG_REDACT_CID") {
+            policy.cid = r;
+        }
+        if let Some(r) = rule("LOG_REDACT_NAMES") {
+            policy.names = r;
+        }
+        if let Some(r) = rule("LOG_REDACT_DETAILS") {
+            policy.details = r;
+        }
+        if let Ok(photo) = std::env::var("LOG_PHOTO") {
+            policy.photo = photo == "true" || photo == "1";
+        }
+        policy
+    }
+}
+
+pub fn set_redaction(policy: RedactionPolicy) {
+    let _ = REDACTION.set(policy);
+}
+
+pub fn redaction() -> RedactionPolicy {
+    *REDACTION.get_or_init(RedactionPolicy::from_env)
+}
+
+/// Keys the `hash` rule. Until it is set, hashed fields are not logged.
+pub fn set_hash_key(key: Vec<u8>) {
+    let _ = HASH_KEY.set(key);
+}
+
+/// Applies `rule` to a value; `None` means the value must not be logged.
+pub fn redact(rule: FieldRule, value: &str) -> Option<String> {
+    match rule {
+        FieldRule::Full => Some(value.to_string()),
+        FieldRule::Mask => Some(mask_text(value)),
+        FieldRule::Hash => hash_value(value),
+        FieldRule::Omit => None,
+    }
+}
+
+/// Like `redact`, but masks in the `1-23xx-xxxxx-xx-3` CID format.
+pub fn redact_cid(rule: FieldRule, cid: &str) -> Option<String> {
+    match rule {
+        FieldRule::Mask => Some(mask_cid(cid)),
+        rule => redact(rule, cid),
+    }
+}
+
+/// `1234567890123` becomes `1-23xx-xxxxx-xx-3`.
+pub fn mask_cid(cid: &str) -> String {
+    let digits: Vec<char> = cid.chars().collect();
+    if digits.len() != 13 {
+        return mask_text(cid);
+    }
+    format!("{}-{}{}xx-xxxxx-xx-{}", digits[0], digits[1], digits[2], digits[12])
+}
+
+/// Keeps the first character only.
+fn mask_text(value: &str) -> String {
+    let mut chars = value.chars();
+    match chars.next() {
+        Some(first) => format!("{}{}", first, "x".repeat(chars.count())),
+        None => String::new(),
+    }
+}
+
+/// Short HMAC-SHA256 digest, enough to correlate log lines without the value.
+/// A plain hash of a CID could be reversed by trying every CID.
+fn hash_value(value: &str) -> Option<String> {
+    HASH_KEY.get().map(|key| hash_with(key, value))
+}
+
+fn hash_with(key: &[u8], value: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(value.as_bytes());
+    let digest = hex::encode(mac.finalize().into_bytes());
+    format!("hmac:{}", &digest[..16])
+}
+
+/// The digest that starts every logged read of `cid`, written whatever the CID
+/// rule so that erasure can find the read.
+pub fn card_key(cid: &str) -> Option<String> {
+    hash_value(cid)
+}
+
+pub fn log_path() -> PathBuf {
     let exe_path = std::env::current_exe().unwrap();
     let mut log_path = PathBuf::from(exe_path.parent().unwrap());
     log_path.push("server_tray.log");
+    log_path
+}
+
+pub fn write_log_line(message: &str) {
+    write_log_lines(&[message]);
+}
+
+/// Writes the lines together, so no other line lands between them.
+pub fn write_log_lines<S: AsRef<str>>(messages: &[S]) {
+    let _guard = LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
 
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(log_path)
+        .open(log_path())
         .unwrap();
 
     let timestamp = chrono::Local::now().format("[%Y-%m-%d %H:%M:%S]").to_string();
-    writeln!(file, "{} {}", timestamp, message).unwrap();
+    for message in messages {
+        writeln!(file, "{} {}", timestamp, message.as_ref()).unwrap();
+    }
+}
+
+/// Parses the `[%Y-%m-%d %H:%M:%S]` prefix written by `write_log_line`.
+pub fn line_timestamp(line: &str) -> Option<chrono::NaiveDateTime> {
+    let stamp = line.strip_prefix('[')?.get(..19)?;
+    chrono::NaiveDateTime::parse_from_str(stamp, "%Y-%m-%d %H:%M:%S").ok()
+}
+
+/// Rewrites the log without the lines for which `drop` returns true and
+/// returns how many were removed.
+pub fn remove_lines<F>(mut drop: F) -> std::io::Result<usize>
+where
+    F: FnMut(&str) -> bool,
+{
+    rewrite(|lines| lines.iter().map(|line| drop(line)).collect())
+}
+
+const CARD_KEY: &str = "Card key";
+
+/// Labels of the lines `card::log_card` writes for one read, the card key
+/// first.
+pub const CARD_FIELDS: [&str; 11] = [
+    CARD_KEY,
+    "CID",
+    "TH Name",
+    "EN Name",
+    "Birth",
+    "Gender",
+    "Issuer",
+    "Issue Date",
+    "Expire Date",
+    "Address",
+    "Photo (partial)",
+];
+
+/// Rewrites the log without every read of `cid`: the whole block of card
+/// lines that starts with its card key, and any other line that contains the
+/// CID in full. Without a hash key only the full-CID lines can be found.
+pub fn remove_card(cid: &str) -> std::io::Result<usize> {
+    let key = card_key(cid);
+    rewrite(|lines| card_lines_to_drop(lines, cid, key.as_deref()))
+}
+
+fn card_lines_to_drop(lines: &[&str], cid: &str, key: Option<&str>) -> Vec<bool> {
+    let mut drop: Vec<bool> = lines.iter().map(|line| line.contains(cid)).collect();
+    let Some(key) = key else {
+        return drop;
+    };
+    let mut start = 0;
+    while start < lines.len() {
+        if card_field(lines[start]) != Some((CARD_KEY, key)) {
+            start += 1;
+            continue;
+        }
+        // A block runs until a line that is not a card field, or the key
+        // line of the next read.
+        let mut end = start + 1;
+        while end < lines.len() && card_field(lines[end]).is_some_and(|(label, _)| label != CARD_KEY) {
+            end += 1;
+        }
+        drop[start..end].fill(true);
+        start = end;
+    }
+    drop
+}
+
+pub fn is_card_line(line: &str) -> bool {
+    card_field(line).is_some()
+}
+
+/// The label and value of a card field line.
+fn card_field(line: &str) -> Option<(&'static str, &str)> {
+    let message = line.split_once("] ").map_or(line, |(_, message)| message);
+    CARD_FIELDS
+        .iter()
+        .find_map(|label| Some((*label, message.strip_prefix(label)?.strip_prefix(": ")?)))
+}
+
+/// Rewrites the log without the lines `select` marks and returns how many were
+/// removed.
+fn rewrite<F>(select: F) -> std::io::Result<usize>
+where
+    F: FnOnce(&[&str]) -> Vec<bool>,
+{
+    let _guard = LOG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    let path = log_path();
+    let contents = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let drop = select(&lines);
+    let mut removed = 0;
+    let mut kept = String::with_capacity(contents.len());
+    for (line, drop) in lines.iter().zip(drop) {
+        if drop {
+            removed += 1;
+        } else {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+
+    if removed > 0 {
+        let tmp = path.with_extension("log.tmp");
+        fs::write(&tmp, kept)?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_a_cid_in_the_card_format() {
+        assert_eq!(mask_cid("1234567890123"), "1-23xx-xxxxx-xx-3");
+        assert_eq!(mask_cid("12345"), "1xxxx");
+    }
+
+    #[test]
+    fn applies_each_rule() {
+        assert_eq!(redact(FieldRule::Full, "Somchai").as_deref(), Some("Somchai"));
+        assert_eq!(redact(FieldRule::Mask, "Somchai").as_deref(), Some("Sxxxxxx"));
+        assert_eq!(redact(FieldRule::Mask, "สมชาย").as_deref(), Some("สxxxx"));
+        assert_eq!(redact(FieldRule::Omit, "Somchai"), None);
+        assert_eq!(redact_cid(FieldRule::Mask, "1234567890123").as_deref(), Some("1-23xx-xxxxx-xx-3"));
+    }
+
+    #[test]
+    fn hashes_are_keyed() {
+        let hashed = hash_with(b"test key", "1234567890123");
+        assert!(hashed.starts_with("hmac:"));
+        assert_eq!(hashed.len(), "hmac:".len() + 16);
+        assert_eq!(hash_with(b"test key", "1234567890123"), hashed);
+        assert_ne!(hash_with(b"test key", "1234567890124"), hashed);
+        assert_ne!(hash_with(b"other key", "1234567890123"), hashed);
+    }
+
+    #[test]
+    fn drops_whole_card_blocks_by_card_key_only() {
+        let lines = [
+            "[2025-01-01 10:00:00] Card inserted, reading...",
+            "[2025-01-01 10:00:00] Card key: hmac:1111111111111111",
+            "[2025-01-01 10:00:00] TH Name: hmac:0011223344556677",
+            "[2025-01-01 10:00:00] EN Name: hmac:8899aabbccddeeff",
+            "[2025-01-01 10:01:00] Card removed: ACS",
+            // Another card with the same masked CID
+            "[2025-01-01 10:02:00] Card key: hmac:2222222222222222",
+            "[2025-01-01 10:02:00] CID: 1-23xx-xxxxx-xx-3",
+            "[2025-01-01 10:02:00] Address: 1 Road",
+            "[2025-01-01 10:02:00] Card key: hmac:1111111111111111",
+            "[2025-01-01 10:02:00] CID: 1-23xx-xxxxx-xx-3",
+            "[2025-01-01 10:02:00] Address: 2 Road",
+            "[2025-01-01 10:03:00] Lookup for 1234567890123",
+        ];
+        let drop = card_lines_to_drop(&lines, "1234567890123", Some("hmac:1111111111111111"));
+        assert_eq!(
+            drop,
+            [false, true, true, true, false, false, false, false, true, true, true, true]
+        );
+
+        let unkeyed = card_lines_to_drop(&lines, "1234567890123", None);
+        assert_eq!(unkeyed.iter().filter(|d| **d).count(), 1);
+    }
 }
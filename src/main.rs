@@ -2,17 +2,39 @@
 
 mod server;
 mod thaiid;
+mod auth;
 mod card;
+mod card_api;
+mod card_stream;
+mod cli;
+mod crypto;
+mod error;
+mod events;
+mod hook;
 mod log;
+mod migrator;
+mod origins;
+mod products;
+mod retention;
+mod signing;
+mod staff;
+mod tls;
+mod users;
+mod webhook;
 
 use tray_icon::{
     TrayIconBuilder, Icon, TrayIcon,
-    menu::{Menu, MenuItem, PredefinedMenuItem, MenuEvent, MenuId},
+    menu::{Menu, MenuItem, PredefinedMenuItem, MenuEvent, MenuId, Submenu},
 };
-use tao::event_loop::{EventLoop, ControlFlow};
+use tao::{event::Event, event_loop::{EventLoopBuilder, ControlFlow}};
 use std::{env, net::SocketAddr, path::PathBuf, sync::{Arc, Mutex}};
+use crate::origins::origin;
 use crate::server::{ServerHandle, ServerConfig};
 
+enum UserEvent {
+    OriginPending,
+}
+
 fn load_icon() -> Icon {
     log::write_log_line("Loading embedded icon");
     let img = image::load_from_memory(include_bytes!("../icon.ico"))
@@ -27,8 +49,12 @@ fn load_icon() -> Icon {
 struct MenuIds {
     start: Option<MenuId>,
     stop: Option<MenuId>,
+    pause: Option<MenuId>,
+    resume: Option<MenuId>,
     open: Option<MenuId>,
     exit: Option<MenuId>,
+    /// Approve/deny items: (item, origin id, new status)
+    origins: Vec<(MenuId, i32, origin::Status)>,
 }
 
 #[derive(Default)]
@@ -37,30 +63,60 @@ struct MenuState {
     menu: Option<Menu>,
 }
 
-fn build_menu(running: bool, addr: SocketAddr, ids: &mut MenuIds) -> Menu {
+fn build_menu(
+    running: bool,
+    paused: bool,
+    url: &str,
+    pending: &[origin::Model],
+    ids: &mut MenuIds,
+) -> Menu {
     let menu = Menu::new();
 
     if running {
-        let host_label = format!("http://{}", addr);
-        let m_host = MenuItem::new(&host_label, false, None);
+        let m_host = MenuItem::new(url, false, None);
         let m_open = MenuItem::new("Open in browser", true, None);
+        let m_reader = if paused {
+            MenuItem::new("Resume card reader", true, None)
+        } else {
+            MenuItem::new("Pause card reader", true, None)
+        };
         let m_stop = MenuItem::new("Stop", true, None);
         let m_exit = MenuItem::new("Exit", true, None);
 
         ids.start = None;
         ids.open = Some(m_open.id().clone());
+        if paused {
+            ids.pause = None;
+            ids.resume = Some(m_reader.id().clone());
+        } else {
+            ids.pause = Some(m_reader.id().clone());
+            ids.resume = None;
+        }
         ids.stop = Some(m_stop.id().clone());
         ids.exit = Some(m_exit.id().clone());
 
         menu.append(&m_host).unwrap();
         menu.append(&m_open).unwrap();
+        menu.append(&m_reader).unwrap();
+        if !pending.is_empty() {
+            let m_origins = Submenu::new(format!("Origin requests ({})", pending.len()), true);
+            for o in pending {
+                let m_approve = MenuItem::new(format!("Approve {}", o.origin), true, None);
+                let m_deny = MenuItem::new(format!("Deny {}", o.origin), true, None);
+                ids.origins.push((m_approve.id().clone(), o.id, origin::Status::Approved));
+                ids.origins.push((m_deny.id().clone(), o.id, origin::Status::Denied));
+                m_origins.append(&m_approve).unwrap();
+                m_origins.append(&m_deny).unwrap();
+            }
+            menu.append(&m_origins).unwrap();
+        }
         menu.append(&m_stop).unwrap();
         menu.append(&PredefinedMenuItem::separator()).unwrap();
         menu.append(&m_exit).unwrap();
 
         log::write_log_line(&format!(
-            "Menu(running) open:{:?} stop:{:?} exit:{:?}",
-            m_open.id(), m_stop.id(), m_exit.id()
+            "Menu(running) open:{:?} reader:{:?} stop:{:?} exit:{:?}",
+            m_open.id(), m_reader.id(), m_stop.id(), m_exit.id()
         ));
     } else {
         let m_start = MenuItem::new("Start", true, None);
@@ -69,6 +125,8 @@ fn build_menu(running: bool, addr: SocketAddr, ids: &mut MenuIds) -> Menu {
         ids.start = Some(m_start.id().clone());
         ids.open = None;
         ids.stop = None;
+        ids.pause = None;
+        ids.resume = None;
         ids.exit = Some(m_exit.id().clone());
 
         menu.append(&m_start).unwrap();
@@ -84,21 +142,37 @@ fn build_menu(running: bool, addr: SocketAddr, ids: &mut MenuIds) -> Menu {
     menu
 }
 
-fn refresh_menu(tray: &TrayIcon, running: bool, addr: SocketAddr, state: &Arc<Mutex<MenuState>>) {
+fn refresh_menu(tray: &TrayIcon, handle: &ServerHandle, paused: bool, state: &Arc<Mutex<MenuState>>) {
+    let running = handle.is_running();
+    let pending = handle.pending_origins();
     let mut st = state.lock().unwrap();
     let mut ids = MenuIds::default();
-    let menu = build_menu(running, addr, &mut ids);
+    let menu = build_menu(running, paused, &handle.url(), &pending, &mut ids);
     tray.set_menu(Some(Box::new(menu.clone())));
     st.ids = ids;
     st.menu = Some(menu);
     log::write_log_line("Tray menu refreshed");
 }
 
-fn main() {
-    log::write_log_line("App launched");
+/// The tray build has no console of its own, so command output would be
+/// lost; write it to the console the command was run from instead.
+#[cfg(target_os = "windows")]
+fn attach_console() {
+    const ATTACH_PARENT_PROCESS: u32 = u32::MAX;
+    unsafe extern "system" {
+        fn AttachConsole(process_id: u32) -> i32;
+    }
+    // Fails when started from Explorer, which has no console to attach to.
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
 
-    let event_loop = EventLoop::new();
-    log::write_log_line("Event loop created");
+#[cfg(not(target_os = "windows"))]
+fn attach_console() {}
+
+fn main() {
+    log::set_redaction(log::RedactionPolicy::from_env());
 
     let exe_dir = env::current_exe()
         .unwrap()
@@ -112,10 +186,40 @@ fn main() {
         static_dir: PathBuf::from("assets"),
         address: SocketAddr::from(([127, 0, 0, 1], 8080)),
         db_path: default_db_path.to_string_lossy().to_string(),
+        command_hook: hook::HookConfig::from_env(),
+        retention: retention::RetentionPolicy::from_env(),
+        data_key: crypto::KeySource::from_env(&exe_dir),
+        signing_key: signing::key_path_from_env(&exe_dir),
+        origins: origins::OriginPolicy::from_env(),
+        tls: tls::TlsConfig::from_env(&exe_dir),
+        auth: auth::AuthMode::from_env(),
+        staff: staff::StaffConfig::from_env(),
     };
 
-    let handle = ServerHandle::new(config);
-    let card_listener = card::CardListener::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        attach_console();
+    }
+    if let Some(code) = cli::run(&args, &config) {
+        std::process::exit(code);
+    }
+
+    log::write_log_line("App launched");
+    match crypto::DataCipher::load(&config.data_key) {
+        Ok(cipher) => log::set_hash_key(cipher.log_key()),
+        Err(e) => log::write_log_line(&format!("Data key unavailable, hashed log fields are left out: {}", e)),
+    }
+
+    let event_loop = EventLoopBuilder::<UserEvent>::with_user_event().build();
+    log::write_log_line("Event loop created");
+
+    let cards = card::CardStore::new();
+    let events = events::EventBus::new();
+    let proxy = Mutex::new(event_loop.create_proxy());
+    let handle = ServerHandle::new(config, cards.clone(), events.clone()).on_origin_pending(move || {
+        let _ = proxy.lock().unwrap().send_event(UserEvent::OriginPending);
+    });
+    let card_listener = card::CardListener::new(card::ListenerConfig::from_env(), cards, events);
 
     let tray = TrayIconBuilder::new()
         .with_icon(load_icon())
@@ -124,18 +228,23 @@ fn main() {
         .unwrap();
     log::write_log_line("Tray built successfully");
 
-    handle.start();
-    card_listener.start();
+    if handle.start().is_ok() {
+        card_listener.start();
+    }
 
     let state = Arc::new(Mutex::new(MenuState::default()));
-    refresh_menu(&tray, handle.is_running(), handle.address(), &state);
+    refresh_menu(&tray, &handle, card_listener.is_paused(), &state);
 
     event_loop.run({
         let tray = tray.clone();
         let state = state.clone();
-        move |_, _, control_flow| {
+        move |event, _, control_flow| {
             *control_flow = ControlFlow::Wait;
 
+        if let Event::UserEvent(UserEvent::OriginPending) = event {
+            refresh_menu(&tray, &handle, card_listener.is_paused(), &state);
+        }
+
         while let Ok(m_evt) = MenuEvent::receiver().try_recv() {
             log::write_log_line(&format!("Menu: {:?}", m_evt.id));
 
@@ -144,16 +253,25 @@ fn main() {
             let evt_id = &m_evt.id; // borrow, no move
 
             if Some(evt_id) == ids.start.as_ref() {
-                handle.start();
-                card_listener.start();
-                refresh_menu(&tray, true, handle.address(), &state);
+                if handle.start().is_ok() {
+                    card_listener.start();
+                }
+                refresh_menu(&tray, &handle, card_listener.is_paused(), &state);
+            } else if Some(evt_id) == ids.pause.as_ref() {
+                card_listener.pause();
+                refresh_menu(&tray, &handle, true, &state);
+            } else if Some(evt_id) == ids.resume.as_ref() {
+                card_listener.resume();
+                refresh_menu(&tray, &handle, false, &state);
             } else if Some(evt_id) == ids.stop.as_ref() {
                 card_listener.stop();
                 handle.stop();
-                refresh_menu(&tray, false, handle.address(), &state);
+                refresh_menu(&tray, &handle, card_listener.is_paused(), &state);
+            } else if let Some((_, id, status)) = ids.origins.iter().find(|(item, _, _)| item == evt_id) {
+                handle.set_origin_status(*id, *status);
+                refresh_menu(&tray, &handle, card_listener.is_paused(), &state);
             } else if Some(evt_id) == ids.open.as_ref() {
-                let url = format!("http://{}", handle.address());
-                let _ = open::that(url);
+                let _ = open::that(handle.browser_url());
             } else if Some(evt_id) == ids.exit.as_ref() {
                 log::write_log_line("Exit clicked");
                 card_listener.stop();
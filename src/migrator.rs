@@ -0,0 +1,116 @@
+use crate::auth::ApiTokenMigration;
+use crate::log;
+use crate::origins::{OriginMigration, OriginStatusMigration};
+use crate::server::{
+    CardReadCidDigestMigration, CardReadCidIndexMigration, CardReadMigration, ProductMigration,
+    ProductNameIndexMigration,
+};
+use crate::staff::{StaffCardMigration, StaffPinLockoutMigration};
+use crate::users::UserMigration;
+use crate::webhook::WebhookMigration;
+use chrono::{DateTime, Utc};
+use sea_orm::{DatabaseConnection, DbErr};
+use sea_orm_migration::{MigrationTrait, MigratorTrait};
+use std::collections::HashMap;
+
+/// Every schema change in the order it is applied. Applied names are recorded
+/// in `seaql_migrations`, so a migration is never renamed or reordered once
+/// released; schema changes are new entries at the end.
+///
+/// Databases created before the version table existed already have some of
+/// these tables. The early migrations only create what is missing, so the
+/// first upgrade runs them all and records them.
+pub struct Migrator;
+
+#[async_trait::async_trait]
+impl MigratorTrait for Migrator {
+    fn migrations() -> Vec<Box<dyn MigrationTrait>> {
+        vec![
+            Box::new(ProductMigration),
+            Box::new(WebhookMigration),
+            Box::new(CardReadMigration),
+            Box::new(CardReadCidDigestMigration),
+            Box::new(OriginMigration),
+            Box::new(OriginStatusMigration),
+            Box::new(ApiTokenMigration),
+            Box::new(UserMigration),
+            Box::new(StaffCardMigration),
+            Box::new(CardReadCidIndexMigration),
+            Box::new(StaffPinLockoutMigration),
+            Box::new(ProductNameIndexMigration),
+        ]
+    }
+}
+
+pub enum State {
+    Applied(DateTime<Utc>),
+    Pending,
+    /// Recorded in the database but unknown to this build, i.e. applied by a
+    /// newer version.
+    Unknown(DateTime<Utc>),
+}
+
+/// Every known migration in order, followed by any unknown ones.
+pub async fn status(db: &DatabaseConnection) -> Result<Vec<(String, State)>, DbErr> {
+    let mut applied: HashMap<String, DateTime<Utc>> = Migrator::get_migration_models(db)
+        .await?
+        .into_iter()
+        .map(|model| (model.version, DateTime::from_timestamp(model.applied_at, 0).unwrap_or_default()))
+        .collect();
+    let mut status: Vec<(String, State)> = Migrator::migrations()
+        .iter()
+        .map(|m| {
+            let state = match applied.remove(m.name()) {
+                Some(at) => State::Applied(at),
+                None => State::Pending,
+            };
+            (m.name().to_string(), state)
+        })
+        .collect();
+    let mut unknown: Vec<_> = applied.into_iter().collect();
+    unknown.sort();
+    status.extend(unknown.into_iter().map(|(name, at)| (name, State::Unknown(at))));
+    Ok(status)
+}
+
+/// Names of the migrations in `state`, in order.
+pub async fn names(db: &DatabaseConnection, state: fn(&State) -> bool) -> Result<Vec<String>, DbErr> {
+    Ok(status(db)
+        .await?
+        .into_iter()
+        .filter(|(_, s)| state(s))
+        .map(|(name, _)| name)
+        .collect())
+}
+
+/// Fails when the database was upgraded by a newer version, rather than run
+/// against a schema this build does not understand.
+pub async fn check_known(db: &DatabaseConnection) -> Result<(), DbErr> {
+    let unknown = names(db, |s| matches!(s, State::Unknown(_))).await?;
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    Err(DbErr::Custom(format!(
+        "database has migrations this version does not know ({}); it was upgraded by a newer version",
+        unknown.join(", ")
+    )))
+}
+
+/// Run at startup: applies pending migrations, after `check_known`.
+pub async fn upgrade(db: &DatabaseConnection) -> Result<(), DbErr> {
+    check_known(db).await?;
+    let pending = names(db, |s| matches!(s, State::Pending)).await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    log::write_log_line(&format!("Applying migrations: {}", pending.join(", ")));
+    Migrator::up(db, None).await
+}
+
+/// A migrated in-memory database, for tests.
+#[cfg(test)]
+pub async fn test_db() -> DatabaseConnection {
+    let db = sea_orm::Database::connect("sqlite::memory:").await.unwrap();
+    upgrade(&db).await.unwrap();
+    db
+}
@@ -0,0 +1,750 @@
+use crate::auth::{Auth, Scope};
+use crate::error::{self, ApiError};
+use crate::log;
+use crate::products;
+use crate::signing::{JWS_HEADER, Signer, signed_json, signed_reply};
+use askama::Template;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::Utc;
+use crypto_box::{PublicKey, aead::OsRng};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder, Set,
+};
+use sea_orm_migration::{
+    prelude::{Iden, MigrationName, MigrationTrait, SchemaManager, Table},
+    sea_query,
+    sea_query::ColumnDef,
+};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+use warp::{
+    Filter, Rejection, Reply,
+    http::{HeaderValue, StatusCode, header},
+};
+
+/// libsodium `crypto_box_seal`: X25519 + XSalsa20-Poly1305.
+const SEALED_ALG: &str = "x25519-xsalsa20poly1305-sealed";
+/// Unknown origins beyond this many waiting for approval are rejected without
+/// being recorded, so a page cycling `Origin` headers cannot fill the table.
+const MAX_PENDING: usize = 20;
+
+// Registered web application origin
+pub mod origin {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, serde::Serialize, serde::Deserialize)]
+    #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(16))")]
+    #[serde(rename_all = "snake_case")]
+    pub enum Status {
+        #[sea_orm(string_value = "pending")]
+        Pending,
+        #[sea_orm(string_value = "approved")]
+        Approved,
+        #[sea_orm(string_value = "denied")]
+        Denied,
+    }
+
+    impl Status {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                Status::Pending => "pending",
+                Status::Approved => "approved",
+                Status::Denied => "denied",
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "origins")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        /// `scheme://host[:port]`, as sent in the `Origin` header.
+        #[sea_orm(unique)]
+        pub origin: String,
+        /// Base64 X25519 public key; card data for this origin is sealed to it.
+        pub public_key: Option<String>,
+        pub status: Status,
+        pub created_at: DateTimeUtc,
+        pub updated_at: DateTimeUtc,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+// Migration
+pub struct OriginMigration;
+
+impl MigrationName for OriginMigration {
+    fn name(&self) -> &str {
+        "m0005_create_origins"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for OriginMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Origins::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Origins::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Origins::Origin).string().not_null().unique_key())
+                    .col(ColumnDef::new(Origins::PublicKey).string())
+                    .col(ColumnDef::new(Origins::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(Origins::UpdatedAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_table(Table::drop().table(Origins::Table).to_owned())
+            .await
+    }
+}
+
+// Allow-list: origins registered before it existed stay approved
+pub struct OriginStatusMigration;
+
+impl MigrationName for OriginStatusMigration {
+    fn name(&self) -> &str {
+        "m0006_origins_status"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for OriginStatusMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        if manager.has_column("origins", "status").await? {
+            return Ok(());
+        }
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Origins::Table)
+                    .add_column(
+                        ColumnDef::new(Origins::Status)
+                            .string_len(16)
+                            .not_null()
+                            .default("approved"),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(Origins::Table)
+                    .drop_column(Origins::Status)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum Origins {
+    Table,
+    Id,
+    Origin,
+    PublicKey,
+    Status,
+    CreatedAt,
+    UpdatedAt,
+}
+
+/// Which browser origins may call the API.
+///
+/// `ORIGIN_ALLOWLIST` is a comma-separated list approved on start. Other
+/// origins are held as pending until an operator approves them, or rejected
+/// outright with `ORIGIN_CONSENT=false`.
+#[derive(Clone, Debug)]
+pub struct OriginPolicy {
+    pub allowlist: Vec<String>,
+    pub consent: bool,
+    /// Host names, besides `localhost` and IP addresses, that requests may
+    /// address this server by.
+    pub hosts: Vec<String>,
+}
+
+impl OriginPolicy {
+    pub fn from_env() -> Self {
+        let allowlist = std::env::var("ORIGIN_ALLOWLIST")
+            .unwrap_or_default()
+            .split(',')
+            .filter_map(normalize)
+            .collect();
+        let consent = !matches!(std::env::var("ORIGIN_CONSENT").as_deref(), Ok("false") | Ok("0"));
+        let hosts = std::env::var("ALLOWED_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|h| h.trim().to_ascii_lowercase())
+            .filter(|h| !h.is_empty())
+            .collect();
+        Self { allowlist, consent, hosts }
+    }
+}
+
+/// Lowercases and drops a trailing slash; `None` unless it is an http(s)
+/// origin without a path.
+pub fn normalize(origin: &str) -> Option<String> {
+    let origin = origin.trim().trim_end_matches('/').to_ascii_lowercase();
+    let rest = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))?;
+    (!rest.is_empty() && !rest.contains('/')).then_some(origin)
+}
+
+/// The name in a `Host` header, lowercased and without the port.
+fn host_name(host: &str) -> String {
+    let host = host.trim().to_ascii_lowercase();
+    if let Some(v6) = host.strip_prefix('[') {
+        return v6.split(']').next().unwrap_or_default().to_string();
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.chars().all(|c| c.is_ascii_digit()) => name.to_string(),
+        _ => host,
+    }
+}
+
+fn parse_key(b64: &str) -> Option<PublicKey> {
+    let bytes = general_purpose::STANDARD.decode(b64.trim()).ok()?;
+    PublicKey::from_slice(&bytes).ok()
+}
+
+/// Called when a new origin is waiting for approval.
+pub type PendingNotifier = Arc<dyn Fn() + Send + Sync>;
+
+#[derive(Clone)]
+struct Entry {
+    status: origin::Status,
+    key: Option<PublicKey>,
+}
+
+impl Entry {
+    fn from_model(row: &origin::Model) -> Self {
+        Self {
+            status: row.status,
+            key: row.public_key.as_deref().and_then(parse_key),
+        }
+    }
+}
+
+/// Registered origins, cached for the request path.
+#[derive(Clone)]
+pub struct OriginRegistry {
+    db: DatabaseConnection,
+    policy: OriginPolicy,
+    /// Pages served by this app; they need no approval.
+    own: Vec<String>,
+    entries: Arc<RwLock<HashMap<String, Entry>>>,
+    on_pending: Option<PendingNotifier>,
+}
+
+impl OriginRegistry {
+    pub async fn load(
+        db: DatabaseConnection,
+        policy: OriginPolicy,
+        own: Vec<String>,
+        on_pending: Option<PendingNotifier>,
+    ) -> Result<Self, DbErr> {
+        let registry = Self {
+            own,
+            db,
+            policy,
+            entries: Arc::new(RwLock::new(HashMap::new())),
+            on_pending,
+        };
+        for name in registry.policy.allowlist.clone() {
+            registry.upsert(&name, Some(origin::Status::Approved), None).await?;
+        }
+        let rows = origin::Entity::find().all(&registry.db).await?;
+        *registry.entries.write().unwrap() = rows
+            .iter()
+            .map(|row| (row.origin.clone(), Entry::from_model(row)))
+            .collect();
+        Ok(registry)
+    }
+
+    /// Only approved origins get their data sealed; the others never get it.
+    pub fn sealing_key(&self, origin: Option<&str>) -> Option<PublicKey> {
+        let origin = normalize(origin?)?;
+        let entries = self.entries.read().unwrap();
+        let entry = entries.get(&origin)?;
+        (entry.status == origin::Status::Approved)
+            .then(|| entry.key.clone())
+            .flatten()
+    }
+
+    fn cache(&self, row: &origin::Model) {
+        self.entries
+            .write()
+            .unwrap()
+            .insert(row.origin.clone(), Entry::from_model(row));
+    }
+
+    /// Creates or updates the row for `name`. `status` and `key` are left as
+    /// they are when `None`; `key` is `Some(None)` to clear the key.
+    async fn upsert(
+        &self,
+        name: &str,
+        status: Option<origin::Status>,
+        key: Option<Option<String>>,
+    ) -> Result<origin::Model, DbErr> {
+        let now = Utc::now();
+        let existing = origin::Entity::find()
+            .filter(origin::Column::Origin.eq(name))
+            .one(&self.db)
+            .await?;
+        let row = match existing {
+            Some(row) => {
+                let mut row: origin::ActiveModel = row.into();
+                if let Some(status) = status {
+                    row.status = Set(status);
+                }
+                if let Some(key) = key {
+                    row.public_key = Set(key);
+                }
+                row.updated_at = Set(now);
+                row.update(&self.db).await?
+            }
+            None => {
+                origin::ActiveModel {
+                    origin: Set(name.to_string()),
+                    public_key: Set(key.flatten()),
+                    status: Set(status.unwrap_or(origin::Status::Pending)),
+                    created_at: Set(now),
+                    updated_at: Set(now),
+                    ..Default::default()
+                }
+                .insert(&self.db)
+                .await?
+            }
+        };
+        self.cache(&row);
+        Ok(row)
+    }
+
+    fn pending_count(&self) -> usize {
+        self.entries
+            .read()
+            .unwrap()
+            .values()
+            .filter(|e| e.status == origin::Status::Pending)
+            .count()
+    }
+
+    pub async fn pending(&self) -> Result<Vec<origin::Model>, DbErr> {
+        origin::Entity::find()
+            .filter(origin::Column::Status.eq(origin::Status::Pending))
+            .order_by_asc(origin::Column::CreatedAt)
+            .all(&self.db)
+            .await
+    }
+
+    pub async fn set_status(&self, id: i32, status: origin::Status) -> Result<Option<origin::Model>, DbErr> {
+        let Some(row) = origin::Entity::find_by_id(id).one(&self.db).await? else {
+            return Ok(None);
+        };
+        let name = row.origin.clone();
+        let mut row: origin::ActiveModel = row.into();
+        row.status = Set(status);
+        row.updated_at = Set(Utc::now());
+        let row = row.update(&self.db).await?;
+        self.cache(&row);
+        log::write_log_line(&format!("Origin {} {}", name, status.as_str()));
+        Ok(Some(row))
+    }
+
+    /// Whether `origin` is one of this app's own pages.
+    pub fn is_own(&self, origin: &str) -> bool {
+        normalize(origin).is_some_and(|name| self.own.contains(&name))
+    }
+
+    /// Guards against DNS rebinding: a page whose own name resolves to this
+    /// machine is same-origin with it, but still sends that name in `Host`.
+    /// Only IP addresses, `localhost` and `ALLOWED_HOSTS` pass; requests
+    /// without `Host` do not come from a browser.
+    fn check_host(&self, host: Option<&str>) -> Result<(), Rejection> {
+        let Some(host) = host else {
+            return Ok(());
+        };
+        let name = host_name(host);
+        if name == "localhost" || name.parse::<IpAddr>().is_ok() || self.policy.hosts.contains(&name) {
+            return Ok(());
+        }
+        Err(ApiError::Forbidden(format!("host {} is not allowed", name)).into())
+    }
+
+    /// Decides whether a request may proceed and returns the origin to echo
+    /// in CORS headers. Requests without `Origin` (same-origin navigation,
+    /// non-browser clients) and from this app's own pages pass as is.
+    async fn admit(&self, origin: Option<String>) -> Result<Option<String>, Rejection> {
+        let Some(raw) = origin else {
+            return Ok(None);
+        };
+        let reject = |origin: &str, reason: &str| {
+            Err(ApiError::Forbidden(format!("origin {} {}", origin, reason)).into())
+        };
+        let Some(name) = normalize(&raw) else {
+            return reject(&raw, "is not allowed");
+        };
+        if self.own.contains(&name) {
+            return Ok(None);
+        }
+
+        let status = self.entries.read().unwrap().get(&name).map(|e| e.status);
+        match status {
+            Some(origin::Status::Approved) => Ok(Some(raw)),
+            Some(origin::Status::Pending) => reject(&name, "is waiting for approval"),
+            Some(origin::Status::Denied) => reject(&name, "is not allowed"),
+            None if self.policy.consent && self.pending_count() >= MAX_PENDING => {
+                reject(&name, "is not allowed; too many origins are waiting for approval")
+            }
+            None if self.policy.consent => {
+                match self.upsert(&name, None, None).await {
+                    Ok(row) => {
+                        log::write_log_line(&format!("Origin {} is waiting for approval (id {})", name, row.id));
+                        if let Some(notify) = &self.on_pending {
+                            notify();
+                        }
+                    }
+                    Err(e) => log::write_log_line(&format!("Origin request for {} failed: {}", name, e)),
+                }
+                reject(&name, "is waiting for approval")
+            }
+            None => reject(&name, "is not allowed"),
+        }
+    }
+}
+
+fn with_cors(origin: Option<String>, reply: impl Reply) -> warp::reply::Response {
+    let mut res = reply.into_response();
+    if let Some(value) = origin.and_then(|o| HeaderValue::from_str(&o).ok()) {
+        let headers = res.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, HeaderValue::from_static(JWS_HEADER));
+        headers.append(
+            header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static(products::TOTAL_COUNT_HEADER),
+        );
+        headers.append(header::VARY, HeaderValue::from_static("origin"));
+    }
+    res
+}
+
+async fn preflight(
+    host: Option<String>,
+    origin: Option<String>,
+    request_headers: Option<String>,
+    registry: OriginRegistry,
+) -> Result<warp::reply::Response, Rejection> {
+    registry.check_host(host.as_deref())?;
+    let origin = registry.admit(origin).await?;
+    let mut res = with_cors(origin, StatusCode::NO_CONTENT);
+    let headers = res.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST, PUT, PATCH, DELETE, OPTIONS"),
+    );
+    if let Some(value) = request_headers.and_then(|h| HeaderValue::from_str(&h).ok()) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, value);
+    }
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+    Ok(res)
+}
+
+/// Applies the allow-list to `routes`: rejects requests addressed to a host
+/// name this server does not answer to, answers CORS preflights, rejects
+/// origins that are not approved with 403 and adds CORS headers for the
+/// approved ones. `routes` must already be recovered, so that error replies
+/// get CORS headers too.
+pub fn protect<F, R>(
+    registry: OriginRegistry,
+    routes: F,
+) -> impl Filter<Extract = (impl Reply,), Error = Infallible> + Clone
+where
+    F: Filter<Extract = (R,), Error = Infallible> + Clone + Send + Sync + 'static,
+    R: Reply,
+{
+    let registry_filter = warp::any().map(move || registry.clone());
+    let host = warp::header::optional::<String>("host");
+    let origin = warp::header::optional::<String>("origin");
+
+    let preflight = warp::options()
+        .and(warp::header::<String>("access-control-request-method"))
+        .and(host)
+        .and(origin)
+        .and(warp::header::optional::<String>("access-control-request-headers"))
+        .and(registry_filter.clone())
+        .and_then(|_method: String, host, origin, request_headers, registry| {
+            preflight(host, origin, request_headers, registry)
+        });
+
+    let checked = host
+        .and(origin)
+        .and(registry_filter)
+        .and_then(|host: Option<String>, origin, registry: OriginRegistry| async move {
+            registry.check_host(host.as_deref())?;
+            registry.admit(origin).await
+        })
+        .and(routes)
+        .map(with_cors);
+
+    preflight.or(checked).recover(error::recover)
+}
+
+#[derive(serde::Serialize)]
+struct Sealed {
+    alg: &'static str,
+    content_type: &'static str,
+    /// Base64 sealed box: ephemeral public key || ciphertext.
+    ciphertext: String,
+}
+
+/// How card data is returned to the requesting origin: sealed to its key
+/// when it registered one, then signed.
+#[derive(Clone)]
+pub struct Envelope {
+    signer: Signer,
+    key: Option<PublicKey>,
+}
+
+impl Envelope {
+    fn seal(&self, body: &[u8], content_type: &'static str) -> Option<Sealed> {
+        let key = self.key.as_ref()?;
+        let ciphertext = key
+            .seal(&mut OsRng, body)
+            .expect("sealing does not fail for in-memory buffers");
+        Some(Sealed {
+            alg: SEALED_ALG,
+            content_type,
+            ciphertext: general_purpose::STANDARD.encode(ciphertext),
+        })
+    }
+
+    pub fn reply(&self, body: Vec<u8>, content_type: &'static str) -> warp::reply::Response {
+        match self.seal(&body, content_type) {
+            Some(sealed) => signed_json(&self.signer, &sealed),
+            None => signed_reply(&self.signer, body, content_type),
+        }
+    }
+
+    pub fn json<T: serde::Serialize>(&self, body: &T) -> warp::reply::Response {
+        self.reply(serde_json::to_vec(body).unwrap_or_default(), "application/json")
+    }
+
+    /// One stream message: the JSON, sealed if the origin has a key, and as a
+    /// compact JWS when `signed`.
+    pub fn message(&self, json: String, signed: bool) -> String {
+        let json = match self.seal(json.as_bytes(), "application/json") {
+            Some(sealed) => serde_json::to_string(&sealed).unwrap_or_default(),
+            None => json,
+        };
+        if signed {
+            self.signer.sign(json.as_bytes()).compact()
+        } else {
+            json
+        }
+    }
+}
+
+/// Builds the `Envelope` for the request's `Origin`.
+pub fn envelope(origins: OriginRegistry, signer: Signer) -> impl Filter<Extract = (Envelope,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin").map(move |origin: Option<String>| Envelope {
+        signer: signer.clone(),
+        key: origins.sealing_key(origin.as_deref()),
+    })
+}
+
+// Routes
+#[derive(serde::Deserialize)]
+struct OriginUpdate {
+    origin: String,
+    public_key: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct OriginQuery {
+    status: Option<origin::Status>,
+}
+
+pub fn routes(origins: OriginRegistry, auth: Auth) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = auth.require(Scope::Admin);
+    let origins_filter = warp::any().map(move || origins.clone());
+
+    let list = warp::path!("origins")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(warp::query::<OriginQuery>())
+        .and(origins_filter.clone())
+        .and_then(list_origins);
+
+    let upsert = warp::path!("origins")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(origins_filter.clone())
+        .and_then(upsert_origin);
+
+    let approve = warp::path!("origins" / i32 / "approve")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(origins_filter.clone())
+        .and_then(|id, origins| set_status(id, origin::Status::Approved, origins));
+
+    let deny = warp::path!("origins" / i32 / "deny")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(origins_filter.clone())
+        .and_then(|id, origins| set_status(id, origin::Status::Denied, origins));
+
+    let delete = warp::path!("origins" / i32)
+        .and(warp::delete())
+        .and(admin.clone())
+        .and(origins_filter)
+        .and_then(delete_origin);
+
+    list.or(upsert).or(approve).or(deny).or(delete)
+}
+
+#[derive(Template)]
+#[template(path = "origins.html")]
+struct OriginsTemplate {
+    origins: Vec<origin::Model>,
+}
+
+/// `GET /admin/origins`: approve or deny origins from the browser.
+pub fn admin_routes(origins: OriginRegistry, auth: Auth) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "origins")
+        .and(warp::get())
+        .and(auth.require_page(Scope::Admin))
+        .and(warp::any().map(move || origins.clone()))
+        .and_then(render_admin)
+}
+
+async fn render_admin(origins: OriginRegistry) -> Result<impl Reply, Rejection> {
+    let origins = origin::Entity::find()
+        .order_by_asc(origin::Column::Origin)
+        .all(&origins.db)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::html(OriginsTemplate { origins }.render().unwrap()))
+}
+
+async fn list_origins(query: OriginQuery, origins: OriginRegistry) -> Result<impl Reply, Rejection> {
+    let mut find = origin::Entity::find().order_by_asc(origin::Column::Origin);
+    if let Some(status) = query.status {
+        find = find.filter(origin::Column::Status.eq(status));
+    }
+    let list = find.all(&origins.db).await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&list))
+}
+
+/// Registering an origin through the API approves it.
+async fn upsert_origin(update: OriginUpdate, origins: OriginRegistry) -> Result<impl Reply, Rejection> {
+    let Some(name) = normalize(&update.origin) else {
+        return Err(ApiError::BadRequest("origin must be scheme://host[:port]".to_string()).into());
+    };
+    if update.public_key.as_deref().is_some_and(|k| parse_key(k).is_none()) {
+        return Err(ApiError::BadRequest("public_key must be a base64 32-byte X25519 key".to_string()).into());
+    }
+    let row = origins
+        .upsert(&name, Some(origin::Status::Approved), Some(update.public_key))
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&row))
+}
+
+async fn set_status(id: i32, status: origin::Status, origins: OriginRegistry) -> Result<impl Reply, Rejection> {
+    match origins.set_status(id, status).await.map_err(ApiError::from)? {
+        Some(row) => Ok(warp::reply::json(&row)),
+        None => Err(ApiError::NotFound("origin not found".to_string()).into()),
+    }
+}
+
+async fn delete_origin(id: i32, origins: OriginRegistry) -> Result<impl Reply, Rejection> {
+    let row = origin::Entity::find_by_id(id)
+        .one(&origins.db)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or_else(|| ApiError::NotFound("origin not found".to_string()))?;
+    origin::Entity::delete_by_id(id)
+        .exec(&origins.db)
+        .await
+        .map_err(ApiError::from)?;
+    origins.entries.write().unwrap().remove(&row.origin);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrator;
+
+    #[test]
+    fn normalizes_http_origins() {
+        assert_eq!(normalize(" HTTPS://Shop.Example/ ").as_deref(), Some("https://shop.example"));
+        assert_eq!(normalize("http://localhost:3000").as_deref(), Some("http://localhost:3000"));
+        for bad in ["null", "", "shop.example", "ftp://shop.example", "https://", "https:///", "https://shop.example/pos"] {
+            assert_eq!(normalize(bad), None, "{}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_only_to_its_own_host_names() {
+        let db = migrator::test_db().await;
+        let policy = OriginPolicy {
+            allowlist: Vec::new(),
+            consent: true,
+            hosts: vec!["till.local".to_string()],
+        };
+        let origins = OriginRegistry::load(db, policy, Vec::new(), None).await.unwrap();
+
+        assert!(origins.check_host(None).is_ok());
+        for host in ["127.0.0.1:8080", "[::1]:8080", "LocalHost:8080", "192.168.1.20", "till.local:8443"] {
+            assert!(origins.check_host(Some(host)).is_ok(), "{}", host);
+        }
+        for host in ["rebind.example:8080", "localhost.rebind.example", "till.local.example"] {
+            assert!(origins.check_host(Some(host)).is_err(), "{}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn caps_the_origins_waiting_for_approval() {
+        let db = migrator::test_db().await;
+        let policy = OriginPolicy { allowlist: Vec::new(), consent: true, hosts: Vec::new() };
+        let origins = OriginRegistry::load(db, policy, Vec::new(), None).await.unwrap();
+
+        for n in 0..MAX_PENDING + 5 {
+            let origin = format!("https://shop{}.example", n);
+            assert!(origins.admit(Some(origin)).await.is_err());
+        }
+        let pending = origins.pending().await.unwrap();
+        assert_eq!(pending.len(), MAX_PENDING);
+
+        // Deciding on one makes room for the next
+        origins.set_status(pending[0].id, origin::Status::Denied).await.unwrap();
+        assert!(origins.admit(Some("https://late.example".to_string())).await.is_err());
+        assert!(origins.pending().await.unwrap().iter().any(|o| o.origin == "https://late.example"));
+    }
+}
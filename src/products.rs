@@ -0,0 +1,500 @@
+use crate::auth::{Auth, Scope};
+use crate::error::{ApiError, FieldErrors};
+use crate::server::{ActiveModel, Column, Entity, Model};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, Order, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set, sea_query::LikeExpr,
+};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Deserializer};
+use validator::Validate;
+use warp::{
+    Buf, Filter, Rejection, Reply,
+    http::{HeaderValue, StatusCode, header},
+};
+
+/// Number of products matching a list query, before `limit`/`offset`.
+pub const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Product bodies are a name and a number; anything bigger is refused with 413
+/// before it is parsed.
+const MAX_BODY: usize = 16 * 1024;
+const DEFAULT_LIMIT: u64 = 100;
+const MAX_LIMIT: u64 = 1000;
+
+// Names are trimmed while parsing, so the rules below and the uniqueness check
+// see what will be stored.
+#[derive(Deserialize, Validate)]
+struct NewProduct {
+    #[serde(deserialize_with = "trimmed")]
+    #[validate(length(min = 1, max = 100, message = "must be 1 to 100 characters"))]
+    name: String,
+    #[validate(range(min = 0, message = "must not be negative"))]
+    quantity: i32,
+}
+
+#[derive(Deserialize, Validate)]
+struct ProductPatch {
+    #[serde(default, deserialize_with = "trimmed_opt")]
+    #[validate(length(min = 1, max = 100, message = "must be 1 to 100 characters"))]
+    name: Option<String>,
+    #[validate(range(min = 0, message = "must not be negative"))]
+    quantity: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<u64>,
+    offset: Option<u64>,
+    /// Comma-separated fields, `-` for descending: `name,-quantity`.
+    sort: Option<String>,
+    /// Substring of the name, case-insensitive for ASCII.
+    name: Option<String>,
+    /// Only products with fewer than this many in stock.
+    quantity_below: Option<i32>,
+}
+
+fn trimmed<'de, D: Deserializer<'de>>(d: D) -> Result<String, D::Error> {
+    Ok(String::deserialize(d)?.trim().to_string())
+}
+
+fn trimmed_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(Option::<String>::deserialize(d)?.map(|s| s.trim().to_string()))
+}
+
+/// A JSON body of at most `MAX_BODY` bytes. The size is checked as the body
+/// arrives, so chunked bodies without `Content-Length` are accepted too.
+fn json_body<T: serde::de::DeserializeOwned + Send>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("content-type")
+        .and(warp::body::stream())
+        .and_then(|content_type: Option<String>, body| async move {
+            if content_type.is_some_and(|t| !is_json(&t)) {
+                return Err(ApiError::UnsupportedMediaType("expected application/json".to_string()).into());
+            }
+            let body = read_body(body).await?;
+            serde_json::from_slice::<T>(&body)
+                .map_err(|e| Rejection::from(ApiError::BadRequest(format!("malformed JSON body: {}", e))))
+        })
+}
+
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence.trim().eq_ignore_ascii_case("application/json")
+}
+
+async fn read_body(body: impl Stream<Item = Result<impl Buf, warp::Error>>) -> Result<Vec<u8>, ApiError> {
+    let mut body = std::pin::pin!(body);
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| ApiError::BadRequest(format!("cannot read body: {}", e)))?;
+        if bytes.len() + chunk.remaining() > MAX_BODY {
+            return Err(ApiError::PayloadTooLarge("request body too large".to_string()));
+        }
+        bytes.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(bytes)
+}
+
+/// `products` and `products/{id}`, plus the deprecated `POST product`.
+pub fn routes(db: DatabaseConnection, auth: Auth) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let db_filter = warp::any().map(move || db.clone());
+    let read = auth.require(Scope::ProductsRead);
+    let write = auth.require(Scope::ProductsWrite);
+
+    let list = warp::path!("products")
+        .and(warp::get())
+        .and(read.clone())
+        .and(warp::query::<ListQuery>())
+        .and(db_filter.clone())
+        .and_then(list_products);
+
+    let get = warp::path!("products" / i32)
+        .and(warp::get())
+        .and(read)
+        .and(db_filter.clone())
+        .and_then(get_product);
+
+    let create = warp::path!("products")
+        .and(warp::post())
+        .and(write.clone())
+        .and(json_body())
+        .and(db_filter.clone())
+        .and_then(create_product);
+
+    let replace = warp::path!("products" / i32)
+        .and(warp::put())
+        .and(write.clone())
+        .and(json_body())
+        .and(db_filter.clone())
+        .and_then(replace_product);
+
+    let patch = warp::path!("products" / i32)
+        .and(warp::patch())
+        .and(write.clone())
+        .and(json_body())
+        .and(db_filter.clone())
+        .and_then(patch_product);
+
+    let delete = warp::path!("products" / i32)
+        .and(warp::delete())
+        .and(write.clone())
+        .and(db_filter.clone())
+        .and_then(delete_product);
+
+    // Kept for existing scripts; answers 200 as before, with a pointer to
+    // `POST products`.
+    let legacy_create = warp::path!("product")
+        .and(warp::post())
+        .and(write)
+        .and(json_body())
+        .and(db_filter)
+        .and_then(legacy_create_product);
+
+    list.or(get)
+        .or(create)
+        .or(replace)
+        .or(patch)
+        .or(delete)
+        .or(legacy_create)
+}
+
+/// Names are unique; a taken one is reported against the `name` field like
+/// the other rules.
+async fn check_name(db: &DatabaseConnection, name: &str, except: Option<i32>) -> Result<(), ApiError> {
+    let mut find = Entity::find().filter(Column::Name.eq(name));
+    if let Some(id) = except {
+        find = find.filter(Column::Id.ne(id));
+    }
+    match find.one(db).await? {
+        Some(_) => {
+            let mut errors = FieldErrors::new();
+            errors.insert("name".to_string(), vec!["is already used by another product".to_string()]);
+            Err(ApiError::Invalid(errors))
+        }
+        None => Ok(()),
+    }
+}
+
+/// The unique index stops a write that passed `check_name` alongside another
+/// request for the same name.
+fn name_conflict(e: DbErr, name: &str) -> ApiError {
+    match ApiError::from(e) {
+        ApiError::Conflict(_) => ApiError::Conflict(format!("a product named {} already exists", name)),
+        e => e,
+    }
+}
+
+async fn find(db: &DatabaseConnection, id: i32) -> Result<Model, ApiError> {
+    Entity::find_by_id(id)
+        .one(db)
+        .await?
+        .ok_or_else(|| ApiError::NotFound(format!("product {} not found", id)))
+}
+
+fn sort_order(sort: &str) -> Result<Vec<(Column, Order)>, ApiError> {
+    sort.split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (field, order) = match field.strip_prefix('-') {
+                Some(field) => (field, Order::Desc),
+                None => (field, Order::Asc),
+            };
+            let column = match field {
+                "id" => Column::Id,
+                "name" => Column::Name,
+                "quantity" => Column::Quantity,
+                other => return Err(ApiError::BadRequest(format!("cannot sort by {}", other))),
+            };
+            Ok((column, order))
+        })
+        .collect()
+}
+
+/// Matches `%` and `_` in a name search literally.
+fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The page as a JSON array, with the number of matching products in
+/// `X-Total-Count`.
+async fn list_products(query: ListQuery, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let mut find = Entity::find();
+    if let Some(name) = query.name.as_deref().map(str::trim).filter(|n| !n.is_empty()) {
+        let pattern = LikeExpr::new(format!("%{}%", escape_like(name))).escape('\\');
+        find = find.filter(Column::Name.like(pattern));
+    }
+    if let Some(below) = query.quantity_below {
+        find = find.filter(Column::Quantity.lt(below));
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+    if !(1..=MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::BadRequest(format!("limit must be 1 to {}", MAX_LIMIT)).into());
+    }
+    let total = find.clone().count(&db).await.map_err(ApiError::from)?;
+
+    for (column, order) in sort_order(query.sort.as_deref().unwrap_or("id"))? {
+        find = find.order_by(column, order);
+    }
+    // Ties keep a fixed order so pages neither repeat nor skip rows.
+    let products = find
+        .order_by_asc(Column::Id)
+        .offset(query.offset.unwrap_or(0))
+        .limit(limit)
+        .all(&db)
+        .await
+        .map_err(ApiError::from)?;
+
+    let mut res = warp::reply::json(&products).into_response();
+    res.headers_mut().insert(TOTAL_COUNT_HEADER, HeaderValue::from(total));
+    Ok(res)
+}
+
+async fn get_product(id: i32, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let product = find(&db, id).await?;
+    Ok(warp::reply::json(&product))
+}
+
+async fn insert(db: &DatabaseConnection, new: NewProduct) -> Result<Model, ApiError> {
+    new.validate()?;
+    check_name(db, &new.name, None).await?;
+    let product = ActiveModel {
+        name: Set(new.name.clone()),
+        quantity: Set(new.quantity),
+        ..Default::default()
+    };
+    product.insert(db).await.map_err(|e| name_conflict(e, &new.name))
+}
+
+async fn create_product(new: NewProduct, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let product = insert(&db, new).await?;
+    let mut res = warp::reply::with_status(warp::reply::json(&product), StatusCode::CREATED).into_response();
+    if let Ok(location) = HeaderValue::from_str(&format!("/api/products/{}", product.id)) {
+        res.headers_mut().insert(header::LOCATION, location);
+    }
+    Ok(res)
+}
+
+async fn legacy_create_product(new: NewProduct, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let mut res = match insert(&db, new).await {
+        Ok(product) => warp::reply::json(&product).into_response(),
+        Err(e) => e.to_response(),
+    };
+    let headers = res.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    headers.insert(
+        header::LINK,
+        HeaderValue::from_static("</api/products>; rel=\"successor-version\""),
+    );
+    Ok(res)
+}
+
+async fn replace_product(id: i32, new: NewProduct, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let patch = ProductPatch {
+        name: Some(new.name),
+        quantity: Some(new.quantity),
+    };
+    patch_product(id, patch, db).await
+}
+
+async fn patch_product(id: i32, patch: ProductPatch, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    patch.validate().map_err(ApiError::from)?;
+    let product = find(&db, id).await?;
+    if let Some(name) = &patch.name {
+        check_name(&db, name, Some(id)).await?;
+    }
+    let mut product: ActiveModel = product.into();
+    if let Some(name) = &patch.name {
+        product.name = Set(name.clone());
+    }
+    if let Some(quantity) = patch.quantity {
+        product.quantity = Set(quantity);
+    }
+    let product = product
+        .update(&db)
+        .await
+        .map_err(|e| name_conflict(e, patch.name.as_deref().unwrap_or_default()))?;
+    Ok(warp::reply::json(&product))
+}
+
+/// Sample products for a demo or test database; names already present are
+/// skipped. Returns how many were added.
+pub async fn seed(db: &DatabaseConnection) -> Result<usize, DbErr> {
+    const SAMPLE: [(&str, i32); 5] = [
+        ("Drinking water 600 ml", 48),
+        ("Instant noodles", 30),
+        ("Jasmine rice 5 kg", 12),
+        ("Fish sauce 700 ml", 8),
+        ("Eggs (10 pack)", 0),
+    ];
+    let mut added = 0;
+    for (name, quantity) in SAMPLE {
+        if Entity::find().filter(Column::Name.eq(name)).one(db).await?.is_some() {
+            continue;
+        }
+        ActiveModel {
+            name: Set(name.to_string()),
+            quantity: Set(quantity),
+            ..Default::default()
+        }
+        .insert(db)
+        .await?;
+        added += 1;
+    }
+    Ok(added)
+}
+
+async fn delete_product(id: i32, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let res = Entity::delete_by_id(id).exec(&db).await.map_err(ApiError::from)?;
+    if res.rows_affected == 0 {
+        return Err(ApiError::NotFound(format!("product {} not found", id)).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthMode;
+    use crate::{error, migrator};
+    use futures_util::stream;
+    use warp::hyper::body::Bytes;
+
+    fn app(db: DatabaseConnection) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone {
+        routes(db.clone(), Auth::new(db, AuthMode::Off)).recover(error::recover)
+    }
+
+    #[tokio::test]
+    async fn limits_bodies_as_they_arrive() {
+        let chunks = |sizes: &[usize]| {
+            let chunks: Vec<Result<Bytes, warp::Error>> = sizes.iter().map(|n| Ok(Bytes::from(vec![b' '; *n]))).collect();
+            stream::iter(chunks)
+        };
+        assert_eq!(read_body(chunks(&[MAX_BODY / 2, MAX_BODY / 2])).await.unwrap().len(), MAX_BODY);
+        let error = read_body(chunks(&[MAX_BODY / 2, MAX_BODY / 2, 1])).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn create_checks_the_body() {
+        let db = migrator::test_db().await;
+        let routes = app(db);
+        let post = |body: String| {
+            warp::test::request()
+                .method("POST")
+                .path("/products")
+                .header(header::CONTENT_TYPE, "application/json; charset=utf-8")
+                .body(body)
+        };
+
+        let res = post(r#"{"name": "  Rice  ", "quantity": 3}"#.to_string()).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created: Model = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(created.name, "Rice");
+
+        let res = post(r#"{"name": "rice"}"#.to_string()).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let res = post(format!(r#"{{"name": "{}", "quantity": 1}}"#, " ".repeat(MAX_BODY))).reply(&routes).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/products")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(r#"{"name": "Salt", "quantity": 1}"#)
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn validates_names_and_quantities() {
+        let errors = |body: &str| match serde_json::from_str::<NewProduct>(body).unwrap().validate() {
+            Ok(()) => Vec::new(),
+            Err(e) => {
+                let mut fields: Vec<_> = e.field_errors().into_keys().map(|f| f.to_string()).collect();
+                fields.sort();
+                fields
+            }
+        };
+        assert!(errors(r#"{"name": "Rice", "quantity": 0}"#).is_empty());
+        assert_eq!(errors(r#"{"name": "   ", "quantity": 1}"#), ["name"]);
+        assert_eq!(errors(&format!(r#"{{"name": "{}", "quantity": 1}}"#, "x".repeat(101))), ["name"]);
+        assert!(errors(&format!(r#"{{"name": " {} ", "quantity": 1}}"#, "x".repeat(100))).is_empty());
+        assert_eq!(errors(r#"{"name": "", "quantity": -1}"#), ["name", "quantity"]);
+
+        let patch: ProductPatch = serde_json::from_str("{}").unwrap();
+        assert!(patch.validate().is_ok());
+        let patch: ProductPatch = serde_json::from_str(r#"{"name": " "}"#).unwrap();
+        assert!(patch.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn names_stay_unique() {
+        let db = migrator::test_db().await;
+        let rice = insert(&db, NewProduct { name: "Rice".to_string(), quantity: 1 }).await.unwrap();
+        let salt = insert(&db, NewProduct { name: "Salt".to_string(), quantity: 1 }).await.unwrap();
+
+        let taken = insert(&db, NewProduct { name: "Rice".to_string(), quantity: 2 }).await.unwrap_err();
+        assert_eq!(taken.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        // A request that passed check_name alongside another is stopped by the index
+        let raced = ActiveModel {
+            name: Set("Rice".to_string()),
+            quantity: Set(3),
+            ..Default::default()
+        };
+        let raced = name_conflict(raced.insert(&db).await.unwrap_err(), "Rice");
+        assert_eq!(raced.status(), StatusCode::CONFLICT);
+        assert!(matches!(raced, ApiError::Conflict(d) if d == "a product named Rice already exists"));
+        assert!(check_name(&db, "Rice", Some(rice.id)).await.is_ok());
+        assert!(check_name(&db, "Rice", Some(salt.id)).await.is_err());
+    }
+
+    #[test]
+    fn parses_sort_fields() {
+        let order = sort_order(" name, -quantity ,").unwrap();
+        assert_eq!(order.len(), 2);
+        assert!(matches!(order[0], (Column::Name, Order::Asc)));
+        assert!(matches!(order[1], (Column::Quantity, Order::Desc)));
+        assert!(sort_order("").unwrap().is_empty());
+        assert!(sort_order("-id").is_ok_and(|o| matches!(o[..], [(Column::Id, Order::Desc)])));
+        assert!(sort_order("name,price").is_err());
+        assert!(sort_order("--name").is_err());
+    }
+
+    #[tokio::test]
+    async fn lists_with_literal_name_matches_and_bounded_limits() {
+        let db = migrator::test_db().await;
+        for name in ["Rice 100%", "Rice 1000", "rice_bran", "Ricebran"] {
+            insert(&db, NewProduct { name: name.to_string(), quantity: 1 }).await.unwrap();
+        }
+        let routes = app(db);
+        let get = |path: &str| warp::test::request().path(path).reply(&routes);
+
+        let names = |body: &[u8]| {
+            let products: Vec<Model> = serde_json::from_slice(body).unwrap();
+            products.into_iter().map(|p| p.name).collect::<Vec<_>>()
+        };
+        let res = get("/products?name=0%25").await;
+        assert_eq!(names(res.body()), ["Rice 100%"]);
+        let res = get("/products?name=e_b").await;
+        assert_eq!(names(res.body()), ["rice_bran"]);
+        let res = get("/products?name=RICE&sort=-name&limit=2").await;
+        assert_eq!(names(res.body()), ["rice_bran", "Ricebran"]);
+        assert_eq!(res.headers()[TOTAL_COUNT_HEADER], "4");
+
+        for limit in ["0", "1001"] {
+            let res = get(&format!("/products?limit={}", limit)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "limit={}", limit);
+        }
+        assert_eq!(get("/products?limit=1000").await.status(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::auth::{Auth, Scope};
+use crate::card::CardStore;
+use crate::crypto::DataCipher;
+use crate::error::ApiError;
+use crate::events::EventBus;
+use crate::log;
+use crate::server::card_read;
+use crate::users::user;
+use crate::webhook::delivery;
+use chrono::{Local, Utc};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, Condition, DatabaseConnection, DbErr, EntityTrait, QueryFilter, Set,
+    sea_query::Expr,
+};
+use std::time::Duration;
+use warp::{Filter, Rejection, Reply};
+
+/// How long each class of personal data is kept, in days.
+///
+/// - photos: the card photo inside queued webhook payloads
+/// - records: names and CIDs in the read history, outbox payloads and the
+///   card lines in `server_tray.log`
+/// - audit: the remaining read metadata (reader, time, outcome) and the rest
+///   of the log
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub photo_days: i64,
+    pub record_days: i64,
+    pub audit_days: i64,
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            photo_days: 1,
+            record_days: 30,
+            audit_days: 365,
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn from_env() -> Self {
+        let days = |key: &str, default: i64| {
+            std::env::var(key)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .unwrap_or(default)
+                .max(0)
+        };
+        let defaults = Self::default();
+        Self {
+            photo_days: days("RETENTION_PHOTO_DAYS", defaults.photo_days),
+            record_days: days("RETENTION_RECORD_DAYS", defaults.record_days),
+            audit_days: days("RETENTION_AUDIT_DAYS", defaults.audit_days),
+            interval: std::env::var("RETENTION_INTERVAL_MINS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .map(|m| Duration::from_secs(m.max(1) * 60))
+                .unwrap_or(defaults.interval),
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct PurgeReport {
+    pub photos_stripped: u64,
+    pub records_anonymised: u64,
+    pub outbox_deleted: u64,
+    pub audit_deleted: u64,
+    pub log_lines_removed: u64,
+}
+
+#[derive(Debug, Default, serde::Serialize)]
+pub struct EraseReport {
+    pub card_reads_deleted: u64,
+    pub outbox_deleted: u64,
+    pub log_lines_removed: u64,
+    pub staff_cards_unlinked: u64,
+    pub cached_reads_cleared: u64,
+    pub buffered_events_removed: u64,
+}
+
+/// Runs `purge_expired` on the policy's interval, starting immediately.
+pub fn spawn(db: DatabaseConnection, policy: RetentionPolicy, cipher: DataCipher) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(policy.interval);
+        loop {
+            ticker.tick().await;
+            match purge_expired(&db, &policy, &cipher).await {
+                Ok(report) => log::write_log_line(&format!("Retention purge: {:?}", report)),
+                Err(e) => log::write_log_line(&format!("Retention purge failed: {}", e)),
+            }
+        }
+    });
+}
+
+pub async fn purge_expired(
+    db: &DatabaseConnection,
+    policy: &RetentionPolicy,
+    cipher: &DataCipher,
+) -> Result<PurgeReport, DbErr> {
+    let now = Utc::now();
+    let photo_cutoff = now - chrono::Duration::days(policy.photo_days);
+    let record_cutoff = now - chrono::Duration::days(policy.record_days);
+    let audit_cutoff = now - chrono::Duration::days(policy.audit_days);
+    let mut report = PurgeReport::default();
+
+    // Photos (payloads are encrypted, so each row is opened to check)
+    let expired = delivery::Entity::find()
+        .filter(delivery::Column::CreatedAt.lt(photo_cutoff))
+        .all(db)
+        .await?;
+    for row in expired {
+        let Ok(plain) = cipher.decrypt(&row.payload) else {
+            continue;
+        };
+        let Ok(mut payload) = serde_json::from_str::<serde_json::Value>(&plain) else {
+            continue;
+        };
+        let stripped = payload
+            .get_mut("card")
+            .and_then(|c| c.as_object_mut())
+            .and_then(|card| card.remove("photo_base64"))
+            .is_some();
+        if !stripped {
+            continue;
+        }
+        let mut row: delivery::ActiveModel = row.into();
+        row.payload = Set(cipher.encrypt(&payload.to_string()));
+        row.update(db).await?;
+        report.photos_stripped += 1;
+    }
+
+    // Full records
+    report.records_anonymised = card_read::Entity::update_many()
+        .col_expr(card_read::Column::Cid, Expr::value(Option::<String>::None))
+        .col_expr(card_read::Column::CidDigest, Expr::value(Option::<String>::None))
+        .col_expr(card_read::Column::ThName, Expr::value(Option::<String>::None))
+        .col_expr(card_read::Column::EnName, Expr::value(Option::<String>::None))
+        .col_expr(card_read::Column::PhotoHash, Expr::value(Option::<String>::None))
+        .filter(card_read::Column::ReadAt.lt(record_cutoff))
+        .filter(
+            Condition::any()
+                .add(card_read::Column::Cid.is_not_null())
+                .add(card_read::Column::ThName.is_not_null())
+                .add(card_read::Column::EnName.is_not_null())
+                .add(card_read::Column::PhotoHash.is_not_null()),
+        )
+        .exec(db)
+        .await?
+        .rows_affected;
+
+    report.outbox_deleted = delivery::Entity::delete_many()
+        .filter(delivery::Column::CreatedAt.lt(record_cutoff))
+        .exec(db)
+        .await?
+        .rows_affected;
+
+    // Audit metadata
+    report.audit_deleted = card_read::Entity::delete_many()
+        .filter(card_read::Column::ReadAt.lt(audit_cutoff))
+        .exec(db)
+        .await?
+        .rows_affected;
+
+    // Log
+    let record_cutoff_local = record_cutoff.with_timezone(&Local).naive_local();
+    let audit_cutoff_local = audit_cutoff.with_timezone(&Local).naive_local();
+    let mut last_stamp = None;
+    let removed = log::remove_lines(|line| {
+        // Lines without a timestamp belong to the previous entry.
+        if let Some(stamp) = log::line_timestamp(line) {
+            last_stamp = Some(stamp);
+        }
+        let Some(stamp) = last_stamp else { return false };
+        stamp < audit_cutoff_local || (stamp < record_cutoff_local && log::is_card_line(line))
+    });
+    match removed {
+        Ok(n) => report.log_lines_removed = n as u64,
+        Err(e) => log::write_log_line(&format!("Log retention failed: {}", e)),
+    }
+
+    Ok(report)
+}
+
+pub fn is_valid_cid(cid: &str) -> bool {
+    cid.len() == 13 && cid.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Removes everything held about `cid`: read history, queued webhook payloads,
+/// log lines, a staff card link and the in-memory current card and event
+/// buffer.
+pub async fn erase_cid(
+    db: &DatabaseConnection,
+    cipher: &DataCipher,
+    cards: &CardStore,
+    events: &EventBus,
+    cid: &str,
+) -> Result<EraseReport, DbErr> {
+    let mut report = EraseReport {
+        cached_reads_cleared: cards.forget_cid(cid) as u64,
+        buffered_events_removed: events.forget_cid(cid) as u64,
+        ..Default::default()
+    };
+
+    // Rows written before encryption only have the plain CID.
+    report.card_reads_deleted = card_read::Entity::delete_many()
+        .filter(
+            Condition::any()
+                .add(card_read::Column::CidDigest.eq(cipher.cid_digest(cid)))
+                .add(card_read::Column::Cid.eq(cid)),
+        )
+        .exec(db)
+        .await?
+        .rows_affected;
+
+    // The card no longer logs its holder in; the PIN belonged to the card.
+    report.staff_cards_unlinked = user::Entity::update_many()
+        .col_expr(user::Column::CidDigest, Expr::value(Option::<String>::None))
+        .col_expr(user::Column::PinHash, Expr::value(Option::<String>::None))
+        .filter(user::Column::CidDigest.eq(cipher.cid_digest(cid)))
+        .exec(db)
+        .await?
+        .rows_affected;
+
+    let needle = format!("\"cid\":\"{}\"", cid);
+    let mut matching = Vec::new();
+    for row in delivery::Entity::find().all(db).await? {
+        if cipher.decrypt(&row.payload).is_ok_and(|p| p.contains(&needle)) {
+            matching.push(row.id);
+        }
+    }
+    if !matching.is_empty() {
+        report.outbox_deleted = delivery::Entity::delete_many()
+            .filter(delivery::Column::Id.is_in(matching))
+            .exec(db)
+            .await?
+            .rows_affected;
+    }
+
+    match log::remove_card(cid) {
+        Ok(n) => report.log_lines_removed = n as u64,
+        Err(e) => log::write_log_line(&format!("Log erasure failed: {}", e)),
+    }
+
+    log::write_log_line(&format!(
+        "Erased card data on request: {} rows, {} outbox, {} log lines",
+        report.card_reads_deleted, report.outbox_deleted, report.log_lines_removed
+    ));
+    Ok(report)
+}
+
+// Routes
+#[derive(serde::Deserialize)]
+struct EraseRequest {
+    cid: String,
+}
+
+#[derive(Clone)]
+struct Privacy {
+    db: DatabaseConnection,
+    cipher: DataCipher,
+    cards: CardStore,
+    events: EventBus,
+    policy: RetentionPolicy,
+}
+
+pub fn routes(
+    db: DatabaseConnection,
+    cards: CardStore,
+    events: EventBus,
+    policy: RetentionPolicy,
+    cipher: DataCipher,
+    auth: Auth,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = auth.require(Scope::Admin);
+    let privacy = Privacy {
+        db,
+        cipher,
+        cards,
+        events,
+        policy,
+    };
+    let privacy_filter = warp::any().map(move || privacy.clone());
+
+    let erase = warp::path!("privacy" / "erase")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(privacy_filter.clone())
+        .and_then(post_erase);
+
+    let purge = warp::path!("privacy" / "purge")
+        .and(warp::post())
+        .and(admin)
+        .and(privacy_filter)
+        .and_then(post_purge);
+
+    erase.or(purge)
+}
+
+async fn post_erase(req: EraseRequest, privacy: Privacy) -> Result<impl Reply, Rejection> {
+    if !is_valid_cid(&req.cid) {
+        return Err(ApiError::BadRequest("cid must be 13 digits".to_string()).into());
+    }
+    let report = erase_cid(&privacy.db, &privacy.cipher, &privacy.cards, &privacy.events, &req.cid)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&report))
+}
+
+async fn post_purge(privacy: Privacy) -> Result<impl Reply, Rejection> {
+    let report = purge_expired(&privacy.db, &privacy.policy, &privacy.cipher)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&report))
+}
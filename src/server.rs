@@ -1,20 +1,48 @@
+use crate::{
+    auth::{self, Auth, AuthMode, Principal, Scope},
+    card::CardStore,
+    card_api, card_stream,
+    crypto::{DataCipher, KeySource},
+    error::{self, ApiError},
+    events::{CardEvent, EventBus},
+    hook::{self, HookConfig},
+    log, migrator,
+    origins::{self, OriginPolicy, OriginRegistry, PendingNotifier, origin},
+    products,
+    retention::{self, RetentionPolicy},
+    signing::{self, Signer},
+    staff::{self, StaffConfig, Taps},
+    tls::{self, TlsConfig},
+    users,
+    webhook::{self, Webhooks},
+};
 use askama::Template;
+use base64::{Engine as _, engine::general_purpose};
+use chrono::{Local, NaiveDate, TimeZone, Utc};
+use futures_util::{
+    FutureExt,
+    future::{self, OptionFuture},
+};
 use sea_orm::{
     Database, DatabaseConnection, DerivePrimaryKey, DeriveEntityModel, DeriveRelation, EnumIter, Set, entity::*,
-    // query::*,
+    query::*,
 };
 use sea_orm_migration::{
-    prelude::{MigrationTrait, SchemaManager, Table, Iden, DeriveMigrationName},
+    prelude::{MigrationName, MigrationTrait, SchemaManager, Table, Index, Iden},
     sea_query, sea_query::ColumnDef,
 };
+use sha2::{Digest, Sha256};
 use std::{
     fs,
     net::SocketAddr,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{Arc, Mutex, mpsc},
     thread,
 };
-use tokio::{runtime::Runtime, sync::oneshot};
+use tokio::{
+    runtime::Runtime,
+    sync::{broadcast::error::RecvError, oneshot},
+};
 use warp::{Filter, Rejection, Reply};
 
 #[derive(Clone)]
@@ -22,6 +50,32 @@ pub struct ServerConfig {
     pub static_dir: PathBuf,
     pub address: SocketAddr,
     pub db_path: String,
+    pub command_hook: Option<HookConfig>,
+    pub retention: RetentionPolicy,
+    pub data_key: KeySource,
+    pub signing_key: PathBuf,
+    pub origins: OriginPolicy,
+    pub tls: TlsConfig,
+    pub auth: AuthMode,
+    pub staff: StaffConfig,
+}
+
+impl ServerConfig {
+    /// Origins of the pages this server serves itself.
+    pub fn own_origins(&self) -> Vec<String> {
+        let mut own = Vec::new();
+        let mut add = |scheme: &str, addr: SocketAddr| {
+            own.push(format!("{}://{}", scheme, addr));
+            own.push(format!("{}://localhost:{}", scheme, addr.port()));
+        };
+        if self.tls.serves_http() {
+            add("http", self.address);
+        }
+        if self.tls.serves_https() {
+            add("https", self.tls.https_address(self.address));
+        }
+        own
+    }
 }
 
 #[derive(Clone)]
@@ -29,6 +83,12 @@ pub struct ServerHandle {
     handle: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
     shutdown_tx: Arc<Mutex<Option<oneshot::Sender<()>>>>,
     config: ServerConfig,
+    cards: CardStore,
+    events: EventBus,
+    on_origin_pending: Option<PendingNotifier>,
+    // Set while running, for origin decisions made from the tray
+    origins: Arc<Mutex<Option<(OriginRegistry, tokio::runtime::Handle)>>>,
+    setup: users::Setup,
 }
 
 // Product Entity
@@ -47,9 +107,14 @@ pub enum Relation {}
 impl ActiveModelBehavior for ActiveModel {}
 
 // Migration
-#[derive(DeriveMigrationName)]
 pub struct ProductMigration;
 
+impl MigrationName for ProductMigration {
+    fn name(&self) -> &str {
+        "m0001_create_products"
+    }
+}
+
 #[async_trait::async_trait]
 impl MigrationTrait for ProductMigration {
     async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
@@ -79,6 +144,47 @@ impl MigrationTrait for ProductMigration {
     }
 }
 
+/// `products::check_name` only reads before writing, so two requests could
+/// still store the same name; the index makes the second one fail (409).
+/// Duplicates stored before the check existed get their id appended first.
+pub struct ProductNameIndexMigration;
+
+impl MigrationName for ProductNameIndexMigration {
+    fn name(&self) -> &str {
+        "m0012_unique_product_names"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for ProductNameIndexMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .get_connection()
+            .execute_unprepared(
+                "UPDATE products SET name = name || ' (' || id || ')' \
+                 WHERE id NOT IN (SELECT MIN(id) FROM products GROUP BY name)",
+            )
+            .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_products_name")
+                    .table(Products::Table)
+                    .col(Products::Name)
+                    .unique()
+                    .if_not_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_index(Index::drop().name("idx_products_name").table(Products::Table).to_owned())
+            .await
+    }
+}
+
 #[derive(Iden)]
 enum Products {
     Table,
@@ -87,83 +193,488 @@ enum Products {
     Quantity,
 }
 
+// Card Read Entity
+pub mod card_read {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, serde::Serialize, serde::Deserialize)]
+    #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(16))")]
+    #[serde(rename_all = "snake_case")]
+    pub enum Outcome {
+        #[sea_orm(string_value = "success")]
+        Success,
+        #[sea_orm(string_value = "failed")]
+        Failed,
+    }
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "card_reads")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub reader: String,
+        pub read_at: DateTimeUtc,
+        /// `cid`, `th_name` and `en_name` are stored encrypted, see `crypto`.
+        pub cid: Option<String>,
+        /// Keyed digest of the CID for lookups.
+        #[serde(skip)]
+        pub cid_digest: Option<String>,
+        pub th_name: Option<String>,
+        pub en_name: Option<String>,
+        /// Hex SHA-256 of the photo bytes; the photo itself is not stored.
+        pub photo_hash: Option<String>,
+        pub outcome: Outcome,
+        pub error: Option<String>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+pub struct CardReadMigration;
+
+impl MigrationName for CardReadMigration {
+    fn name(&self) -> &str {
+        "m0003_create_card_reads"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for CardReadMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(CardReads::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(CardReads::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(CardReads::Reader).string().not_null())
+                    .col(ColumnDef::new(CardReads::ReadAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(CardReads::Cid).string())
+                    .col(ColumnDef::new(CardReads::ThName).string())
+                    .col(ColumnDef::new(CardReads::EnName).string())
+                    .col(ColumnDef::new(CardReads::PhotoHash).string())
+                    .col(ColumnDef::new(CardReads::Outcome).string_len(16).not_null())
+                    .col(ColumnDef::new(CardReads::Error).string())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_card_reads_read_at")
+                    .table(CardReads::Table)
+                    .col(CardReads::ReadAt)
+                    .if_not_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_table(Table::drop().table(CardReads::Table).to_owned())
+            .await
+    }
+}
+
+// Encryption moved CID lookups to a digest column
+pub struct CardReadCidDigestMigration;
+
+impl MigrationName for CardReadCidDigestMigration {
+    fn name(&self) -> &str {
+        "m0004_card_reads_cid_digest"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for CardReadCidDigestMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        if manager.has_column("card_reads", "cid_digest").await? {
+            return Ok(());
+        }
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(CardReads::Table)
+                    .add_column(ColumnDef::new(CardReads::CidDigest).string())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_card_reads_cid_digest")
+                    .table(CardReads::Table)
+                    .col(CardReads::CidDigest)
+                    .if_not_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        // SQLite refuses to drop an indexed column.
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_card_reads_cid_digest")
+                    .table(CardReads::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(CardReads::Table)
+                    .drop_column(CardReads::CidDigest)
+                    .to_owned(),
+            )
+            .await
+    }
+}
+
+// The CID column is encrypted with a random nonce, so an index on it never
+// serves a lookup; `idx_card_reads_cid_digest` replaced it.
+pub struct CardReadCidIndexMigration;
+
+impl MigrationName for CardReadCidIndexMigration {
+    fn name(&self) -> &str {
+        "m0010_drop_card_reads_cid_index"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for CardReadCidIndexMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("idx_card_reads_cid")
+                    .table(CardReads::Table)
+                    .if_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, _manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum CardReads {
+    Table,
+    Id,
+    Reader,
+    ReadAt,
+    Cid,
+    CidDigest,
+    ThName,
+    EnName,
+    PhotoHash,
+    Outcome,
+    Error,
+}
+
+/// Opens the database, brings its schema up to date and loads the keys the
+/// routes need.
+async fn open(
+    config: &ServerConfig,
+    on_origin_pending: Option<PendingNotifier>,
+) -> Result<(ServerLock, DatabaseConnection, DataCipher, Signer, OriginRegistry), String> {
+    // --- Ensure DB file exists
+    let db_path = PathBuf::from(&config.db_path);
+    if !db_path.exists() {
+        if let Some(parent) = db_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Cannot create {}: {}", parent.display(), e))?;
+        }
+        fs::File::create(&db_path).map_err(|e| format!("Cannot create {}: {}", db_path.display(), e))?;
+    }
+    let lock = ServerLock::acquire(&config.db_path)?;
+
+    let db = Database::connect(format!("sqlite://{}", config.db_path))
+        .await
+        .map_err(|e| format!("DB connection failed: {}", e))?;
+
+    // --- Bring the schema up to date
+    migrator::upgrade(&db)
+        .await
+        .map_err(|e| format!("Database migration failed: {}", e))?;
+
+    let cipher = DataCipher::load(&config.data_key).map_err(|e| format!("Data key unavailable: {}", e))?;
+    let signer = Signer::load(&config.signing_key).map_err(|e| format!("Signing key unavailable: {}", e))?;
+    let origins = OriginRegistry::load(db.clone(), config.origins.clone(), config.own_origins(), on_origin_pending)
+        .await
+        .map_err(|e| format!("Origins unavailable: {}", e))?;
+    Ok((lock, db, cipher, signer, origins))
+}
+
+/// An exclusive lock on `<db path>.lock`, held while a server uses the
+/// database so that the CLI can tell it is running. The OS releases it when
+/// the process exits, so a crash leaves no stale lock behind.
+pub struct ServerLock {
+    _file: fs::File,
+}
+
+impl ServerLock {
+    fn path(db_path: &str) -> PathBuf {
+        PathBuf::from(format!("{}.lock", db_path))
+    }
+
+    pub fn acquire(db_path: &str) -> Result<Self, String> {
+        let path = Self::path(db_path);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { _file: file }),
+            Err(fs::TryLockError::WouldBlock) => Err(format!("Another server is already using {}", db_path)),
+            Err(fs::TryLockError::Error(e)) => Err(format!("Cannot lock {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Whether a running server holds the lock for `db_path`.
+    pub fn is_held(db_path: &str) -> Result<bool, String> {
+        let path = Self::path(db_path);
+        let file = match fs::OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(format!("Cannot open {}: {}", path.display(), e)),
+        };
+        match file.try_lock() {
+            Ok(()) => Ok(false),
+            Err(fs::TryLockError::WouldBlock) => Ok(true),
+            Err(fs::TryLockError::Error(e)) => Err(format!("Cannot lock {}: {}", path.display(), e)),
+        }
+    }
+}
+
 // Server Handle
 impl ServerHandle {
-    pub fn new(config: ServerConfig) -> Self {
+    pub fn new(config: ServerConfig, cards: CardStore, events: EventBus) -> Self {
         Self {
             handle: Arc::new(Mutex::new(None)),
             shutdown_tx: Arc::new(Mutex::new(None)),
             config,
+            cards,
+            events,
+            on_origin_pending: None,
+            origins: Arc::new(Mutex::new(None)),
+            setup: users::Setup::default(),
         }
     }
 
-    pub fn start(&self) {
+    /// Called from the server thread when an unknown origin asks for access.
+    pub fn on_origin_pending(mut self, notify: impl Fn() + Send + Sync + 'static) -> Self {
+        self.on_origin_pending = Some(Arc::new(notify));
+        self
+    }
+
+    /// Starts the server thread and waits until it has opened the database
+    /// and keys. A startup failure is logged and returned, and the server
+    /// stays stopped.
+    pub fn start(&self) -> Result<(), String> {
         let mut handle_guard = self.handle.lock().unwrap();
-        if handle_guard.is_some() {
-            return;
+        if handle_guard.as_ref().is_some_and(|h| !h.is_finished()) {
+            return Ok(());
         }
 
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         *self.shutdown_tx.lock().unwrap() = Some(shutdown_tx);
         let config = self.config.clone();
+        let cards = self.cards.clone();
+        let events = self.events.clone();
+        let on_origin_pending = self.on_origin_pending.clone();
+        let origins_slot = self.origins.clone();
+        let setup = self.setup.clone();
+        let (ready_tx, ready_rx) = mpsc::channel();
 
         let h = thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
+            let rt = match Runtime::new() {
+                Ok(rt) => rt,
+                Err(e) => {
+                    let _ = ready_tx.send(Err(format!("Runtime unavailable: {}", e)));
+                    return;
+                }
+            };
             rt.block_on(async {
-                // --- Ensure DB file exists
-                if !PathBuf::from(&config.db_path).exists() {
-                    if let Some(parent) = PathBuf::from(&config.db_path).parent() {
-                        fs::create_dir_all(parent).unwrap();
+                let (_lock, db, cipher, signer, origins) = match open(&config, on_origin_pending).await {
+                    Ok(opened) => opened,
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
                     }
-                    fs::File::create(&config.db_path).unwrap();
+                };
+                if let Err(e) = setup.refresh(&db).await {
+                    log::write_log_line(&format!("First-run setup check failed: {}", e));
                 }
 
-                let db = Database::connect(format!("sqlite://{}", config.db_path))
-                    .await
-                    .expect("DB connection failed");
-
-                // --- Run migration automatically
-                let schema_manager = SchemaManager::new(&db);
-                ProductMigration.up(&schema_manager).await.unwrap();
+                // --- Background workers
+                tokio::spawn(record_card_reads(db.clone(), events.clone(), cipher.clone()));
+                let webhooks = Webhooks::new(db.clone(), cipher.clone(), signer.clone());
+                webhooks.spawn(events.clone());
+                if let Some(hook_config) = config.command_hook.clone() {
+                    hook::spawn(hook_config, events.clone());
+                }
+                retention::spawn(db.clone(), config.retention.clone(), cipher.clone());
+                let auth = Auth::new(db.clone(), config.auth).with_secure_cookies(config.tls.serves_https());
+                let taps = Taps::default();
+                staff::spawn(db.clone(), events.clone(), cipher.clone(), config.staff.clone(), taps.clone());
+                let staff_routes = staff::routes(
+                    db.clone(),
+                    cipher.clone(),
+                    cards.clone(),
+                    config.staff.clone(),
+                    taps,
+                    origins.clone(),
+                    auth.clone(),
+                );
+                let privacy_routes = retention::routes(
+                    db.clone(),
+                    cards.clone(),
+                    events.clone(),
+                    config.retention.clone(),
+                    cipher.clone(),
+                    auth.clone(),
+                );
 
                 // --- Routes
-                let html_route = warp::path::end().and_then(render_home);
+                let auth_filter = {
+                    let auth = auth.clone();
+                    warp::any().map(move || auth.clone())
+                };
+                let html_route = warp::path::end()
+                    .and(warp::get())
+                    .and(auth.principal())
+                    .and(warp::cookie::optional::<String>(users::CSRF_COOKIE))
+                    .and(auth_filter)
+                    .and_then(render_home);
 
                 let api = warp::path("api");
+                let product_routes = products::routes(db.clone(), auth.clone());
                 let db_filter = warp::any().map(move || db.clone());
+                let cipher_filter = warp::any().map(move || cipher.clone());
 
-                let get_products = warp::path("products")
+                let get_card_reads = warp::path!("card" / "reads")
                     .and(warp::get())
+                    .and(auth.require(Scope::CardRead))
+                    .and(warp::query::<CardReadQuery>())
                     .and(db_filter.clone())
-                    .and_then(get_products);
-
-                let add_product = warp::path("product")
-                    .and(warp::post())
-                    .and(warp::body::json())
-                    .and(db_filter.clone())
-                    .and_then(add_product);
+                    .and(cipher_filter.clone())
+                    .and_then(get_card_reads);
 
                 let routes = html_route
-                    .or(api.and(get_products))
-                    .or(api.and(add_product))
-                    .or(warp::path("assets").and(warp::fs::dir(config.static_dir.clone())));
-
-                let (_, server) =
-                    warp::serve(routes).bind_with_graceful_shutdown(config.address, async {
-                        let _ = shutdown_rx.await;
-                    });
+                    .or(api.and(product_routes))
+                    .or(api.and(get_card_reads))
+                    .or(api.and(card_api::routes(cards, origins.clone(), signer.clone(), auth.clone())))
+                    .or(api.and(webhook::routes(webhooks, auth.clone())))
+                    .or(api.and(privacy_routes))
+                    .or(api.and(signing::routes(signer.clone())))
+                    .or(api.and(origins::routes(origins.clone(), auth.clone())))
+                    .or(api.and(auth::routes(auth.clone())))
+                    .or(users::routes(auth.clone(), setup))
+                    .or(api.and(staff_routes))
+                    .or(api.and(tls::routes(config.tls.dir.clone())))
+                    .or(origins::admin_routes(origins.clone(), auth.clone()))
+                    .or(card_stream::ws_routes(events.clone(), origins.clone(), signer.clone(), auth.clone()))
+                    .or(card_stream::sse_routes(events, origins.clone(), signer, auth))
+                    .or(warp::path("assets").and(warp::fs::dir(config.static_dir.clone())))
+                    .recover(error::recover);
+                let registry = origins.clone();
+                let routes = origins::protect(origins, routes);
+
+                let shutdown = async {
+                    let _ = shutdown_rx.await;
+                }
+                .shared();
+
+                // Bound before reporting ready, so a taken port or a broken
+                // certificate fails `start` instead of leaving a dead listener.
+                let http = config
+                    .tls
+                    .serves_http()
+                    .then(|| {
+                        warp::serve(routes.clone())
+                            .try_bind_with_graceful_shutdown(config.address, shutdown.clone())
+                            .map_err(|e| format!("Cannot listen on {}: {}", config.address, e))
+                    })
+                    .transpose();
+                let https_address = config.tls.https_address(config.address);
+                let https = config
+                    .tls
+                    .serves_https()
+                    .then(|| {
+                        let cert = tls::ensure(&config.tls.dir).map_err(|e| format!("HTTPS unavailable: {}", e))?;
+                        warp::serve(routes)
+                            .tls()
+                            .cert(cert.cert_pem)
+                            .key(cert.key_pem)
+                            .try_bind_with_graceful_shutdown(https_address, shutdown)
+                            .map_err(|e| format!("Cannot listen on {}: {}", https_address, e))
+                    })
+                    .transpose();
+                let (http, https) = match (http, https) {
+                    (Ok(http), Ok(https)) => (http, https),
+                    (Err(e), _) | (_, Err(e)) => {
+                        let _ = ready_tx.send(Err(e));
+                        return;
+                    }
+                };
+                if https.is_some()
+                    && let Ok(status) = tls::status(&config.tls.dir)
+                {
+                    log::write_log_line(&format!(
+                        "HTTPS certificate valid until {}, CA until {}",
+                        status.cert_not_after, status.ca_not_after
+                    ));
+                }
+                let _ = ready_tx.send(Ok(()));
+                *origins_slot.lock().unwrap() = Some((registry, tokio::runtime::Handle::current()));
 
-                server.await;
+                let http = http.map(|(_, server)| server);
+                let https = https.map(|(_, server)| server);
+                future::join(OptionFuture::from(http), OptionFuture::from(https)).await;
             });
         });
 
-        *handle_guard = Some(h);
+        let started = ready_rx
+            .recv()
+            .unwrap_or_else(|_| Err("server thread stopped during startup".to_string()));
+        match started {
+            Ok(()) => {
+                *handle_guard = Some(h);
+                Ok(())
+            }
+            Err(e) => {
+                let _ = h.join();
+                self.shutdown_tx.lock().unwrap().take();
+                log::write_log_line(&format!("Server failed to start: {}", e));
+                Err(e)
+            }
+        }
     }
 
     pub fn stop(&self) {
         let mut handle_guard = self.handle.lock().unwrap();
         let mut shutdown_guard = self.shutdown_tx.lock().unwrap();
 
+        self.origins.lock().unwrap().take();
         if let Some(tx) = shutdown_guard.take() {
             let _ = tx.send(());
         }
@@ -173,12 +684,41 @@ impl ServerHandle {
         }
     }
 
+    /// False once the server thread has ended, even without `stop`.
     pub fn is_running(&self) -> bool {
-        self.handle.lock().unwrap().is_some()
+        self.handle.lock().unwrap().as_ref().is_some_and(|h| !h.is_finished())
+    }
+
+    /// Base URL for the browser, preferring HTTPS when it is served.
+    pub fn url(&self) -> String {
+        if self.config.tls.serves_https() {
+            format!("https://{}", self.config.tls.https_address(self.config.address))
+        } else {
+            format!("http://{}", self.config.address)
+        }
+    }
+
+    /// What the tray opens: `url`, or first-run setup while there are no users.
+    pub fn browser_url(&self) -> String {
+        let url = self.url();
+        self.setup.url(&url).unwrap_or(url)
     }
 
-    pub fn address(&self) -> SocketAddr {
-        self.config.address
+    /// Origins waiting for approval; empty while the server is stopped.
+    pub fn pending_origins(&self) -> Vec<origin::Model> {
+        let Some((origins, rt)) = self.origins.lock().unwrap().clone() else {
+            return Vec::new();
+        };
+        rt.block_on(origins.pending()).unwrap_or_default()
+    }
+
+    pub fn set_origin_status(&self, id: i32, status: origin::Status) {
+        let Some((origins, rt)) = self.origins.lock().unwrap().clone() else {
+            return;
+        };
+        if let Err(e) = rt.block_on(origins.set_status(id, status)) {
+            log::write_log_line(&format!("Origin update failed: {}", e));
+        }
     }
 }
 
@@ -187,69 +727,241 @@ impl ServerHandle {
 #[template(path = "index.html")]
 struct HomeTemplate<'a> {
     application_name: &'a str,
+    user: Option<&'a users::user::Model>,
+    csrf: &'a str,
 }
 
-async fn render_home() -> Result<impl Reply, Rejection> {
+/// Needs a signed-in user once accounts exist; before that, sends the
+/// visitor to the first-run setup.
+async fn render_home(principal: Principal, csrf: Option<String>, auth: Auth) -> Result<impl Reply, Rejection> {
+    let signed_in = match principal {
+        Principal::User(signed_in) => Some(signed_in),
+        _ if auth.mode() == AuthMode::Off => None,
+        _ => match users::any_users(auth.db()).await {
+            Ok(true) => return Ok(users::redirect("/login")),
+            Ok(false) => return Ok(users::redirect("/setup")),
+            Err(e) => return Err(ApiError::from(e).into()),
+        },
+    };
     let template = HomeTemplate {
         application_name: "Simple Shop",
+        user: signed_in.as_ref().map(|s| &s.user),
+        csrf: signed_in.as_ref().map(|s| s.session.csrf_token.as_str()).unwrap_or_default(),
     };
-    Ok(warp::reply::html(template.render().unwrap()))
+    let mut res = warp::reply::html(template.render().unwrap()).into_response();
+    // With API_AUTH=off nobody signs in; the staff card prompt still needs one.
+    if signed_in.is_none()
+        && csrf.is_none()
+        && let Ok(value) = users::visitor_cookie(auth.secure_cookies()).parse()
+    {
+        res.headers_mut().append(warp::http::header::SET_COOKIE, value);
+    }
+    Ok(res)
 }
 
-async fn get_products(db: DatabaseConnection) -> Result<impl Reply, Rejection> {
-    let products = Entity::find().all(&db).await.unwrap();
-    Ok(warp::reply::json(&products))
+// Card read history
+async fn record_card_reads(db: DatabaseConnection, bus: EventBus, cipher: DataCipher) {
+    let mut rx = bus.subscribe();
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                log::write_log_line(&format!("Card read history lagged, {} events dropped", n));
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let row = match &event.event {
+            CardEvent::ReadCompleted { reader, card } => {
+                let photo = general_purpose::STANDARD
+                    .decode(&card.photo_base64)
+                    .unwrap_or_default();
+                card_read::ActiveModel {
+                    reader: Set(reader.clone()),
+                    read_at: Set(event.at.with_timezone(&Utc)),
+                    cid: Set(Some(cipher.encrypt(&card.cid))),
+                    cid_digest: Set(Some(cipher.cid_digest(&card.cid))),
+                    th_name: Set(Some(cipher.encrypt(&card.th_name))),
+                    en_name: Set(Some(cipher.encrypt(&card.en_name))),
+                    photo_hash: Set((!photo.is_empty()).then(|| hex::encode(Sha256::digest(&photo)))),
+                    outcome: Set(card_read::Outcome::Success),
+                    error: Set(None),
+                    ..Default::default()
+                }
+            }
+            CardEvent::ReadFailed { reader, error } => card_read::ActiveModel {
+                reader: Set(reader.clone()),
+                read_at: Set(event.at.with_timezone(&Utc)),
+                cid: Set(None),
+                cid_digest: Set(None),
+                th_name: Set(None),
+                en_name: Set(None),
+                photo_hash: Set(None),
+                outcome: Set(card_read::Outcome::Failed),
+                error: Set(Some(error.clone())),
+                ..Default::default()
+            },
+            _ => continue,
+        };
+
+        if let Err(e) = row.insert(&db).await {
+            log::write_log_line(&format!("Card read history insert failed: {}", e));
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
-struct NewProduct {
-    name: String,
-    quantity: i32,
+struct CardReadQuery {
+    cid: Option<String>,
+    /// Inclusive local dates, `YYYY-MM-DD`.
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    page: Option<u64>,
+    per_page: Option<u64>,
 }
 
-async fn add_product(new: NewProduct, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
-    let product = ActiveModel {
-        name: Set(new.name),
-        quantity: Set(new.quantity),
-        ..Default::default()
-    };
-    let res = product.insert(&db).await.unwrap();
-    Ok(warp::reply::json(&res))
-}
-
-// runner
-// pub fn run_blocking(config: ServerConfig) {
-//     let rt = Runtime::new().unwrap();
-//     rt.block_on(async move {
-//         if !PathBuf::from(&config.db_path).exists() {
-//             if let Some(parent) = PathBuf::from(&config.db_path).parent() {
-//                 fs::create_dir_all(parent).unwrap();
-//             }
-//             fs::File::create(&config.db_path).unwrap();
-//         }
-
-//         let db = Database::connect(format!("sqlite://{}", config.db_path))
-//             .await
-//             .expect("DB connection failed");
-
-//         let schema_manager = SchemaManager::new(&db);
-//         ProductMigration.up(&schema_manager).await.unwrap();
-
-//         let html_route = warp::path::end().and_then(render_home);
-//         let db_filter = warp::any().map(move || db.clone());
-//         let get_products = warp::path("products")
-//             .and(warp::get())
-//             .and(db_filter.clone())
-//             .and_then(get_products);
-
-//         let routes = html_route
-//             .or(warp::path("api").and(get_products))
-//             .or(warp::path("assets").and(warp::fs::dir(config.static_dir.clone())));
-
-//         let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(config.address, async {
-//             let _ = tokio::signal::ctrl_c().await;
-//         });
-
-//         server.await;
-//     });
-// }
+#[derive(serde::Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    page: u64,
+    per_page: u64,
+    total: u64,
+}
+
+fn local_midnight_utc(date: NaiveDate) -> Option<chrono::DateTime<Utc>> {
+    Local
+        .from_local_datetime(&date.and_hms_opt(0, 0, 0)?)
+        .earliest()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+async fn get_card_reads(
+    query: CardReadQuery,
+    db: DatabaseConnection,
+    cipher: DataCipher,
+) -> Result<impl Reply, Rejection> {
+    if let (Some(from), Some(to)) = (query.from, query.to)
+        && from > to
+    {
+        return Err(ApiError::BadRequest("from must not be after to".to_string()).into());
+    }
+    let mut find = card_read::Entity::find().order_by_desc(card_read::Column::ReadAt);
+    if let Some(cid) = query.cid {
+        // Rows written before encryption only have the plain CID.
+        find = find.filter(
+            Condition::any()
+                .add(card_read::Column::CidDigest.eq(cipher.cid_digest(&cid)))
+                .add(card_read::Column::Cid.eq(cid)),
+        );
+    }
+    if let Some(from) = query.from.and_then(local_midnight_utc) {
+        find = find.filter(card_read::Column::ReadAt.gte(from));
+    }
+    if let Some(to) = query.to.and_then(|d| d.succ_opt()).and_then(local_midnight_utc) {
+        find = find.filter(card_read::Column::ReadAt.lt(to));
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 500);
+    let paginator = find.paginate(&db, per_page);
+    let total = paginator.num_items().await.map_err(ApiError::from)?;
+    let mut items = paginator.fetch_page(page - 1).await.map_err(ApiError::from)?;
+    let decrypt = |value: &Option<String>| cipher.decrypt_opt(value.as_deref()).map_err(ApiError::Internal);
+    for item in &mut items {
+        item.cid = decrypt(&item.cid)?;
+        item.th_name = decrypt(&item.th_name)?;
+        item.en_name = decrypt(&item.en_name)?;
+    }
+    Ok(warp::reply::json(&Page { items, page, per_page, total }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sea_orm_migration::MigratorTrait;
+
+    #[tokio::test]
+    async fn duplicate_product_names_are_renamed_before_the_unique_index() {
+        let db = Database::connect("sqlite::memory:").await.unwrap();
+        let migrations = migrator::Migrator::migrations();
+        let before = migrations.iter().position(|m| m.name() == ProductNameIndexMigration.name()).unwrap();
+        migrator::Migrator::up(&db, Some(before as u32)).await.unwrap();
+        for name in ["Rice", "Rice", "Salt"] {
+            let product = ActiveModel {
+                name: Set(name.to_string()),
+                quantity: Set(1),
+                ..Default::default()
+            };
+            product.insert(&db).await.unwrap();
+        }
+
+        migrator::upgrade(&db).await.unwrap();
+        let names: Vec<String> = Entity::find().all(&db).await.unwrap().into_iter().map(|p| p.name).collect();
+        assert_eq!(names, ["Rice", "Rice (2)", "Salt"]);
+    }
+
+    #[test]
+    fn the_lock_shows_a_running_server_until_released() {
+        let db_path = std::env::temp_dir()
+            .join(format!("server_tray_lock_{}.sqlite", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        assert!(!ServerLock::is_held(&db_path).unwrap());
+
+        let lock = ServerLock::acquire(&db_path).unwrap();
+        assert!(ServerLock::is_held(&db_path).unwrap());
+        assert!(ServerLock::acquire(&db_path).is_err());
+
+        drop(lock);
+        assert!(!ServerLock::is_held(&db_path).unwrap());
+        fs::remove_file(format!("{}.lock", db_path)).unwrap();
+    }
+
+    fn test_config(dir: &std::path::Path, db_path: &std::path::Path, address: SocketAddr) -> ServerConfig {
+        ServerConfig {
+            static_dir: PathBuf::from("assets"),
+            address,
+            db_path: db_path.to_string_lossy().to_string(),
+            command_hook: None,
+            retention: RetentionPolicy::from_env(),
+            data_key: KeySource::from_env(dir),
+            signing_key: signing::key_path_from_env(dir),
+            origins: OriginPolicy::from_env(),
+            tls: TlsConfig::from_env(dir),
+            auth: AuthMode::from_env(),
+            staff: StaffConfig::from_env(),
+        }
+    }
+
+    #[test]
+    fn start_reports_a_database_that_cannot_open() {
+        let dir = std::env::temp_dir().join(format!("server_tray_start_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // A directory, so SQLite cannot open it
+        let config = test_config(&dir, &dir, SocketAddr::from(([127, 0, 0, 1], 0)));
+        let handle = ServerHandle::new(config, CardStore::new(), EventBus::new());
+
+        let error = handle.start().unwrap_err();
+        assert!(error.starts_with("DB connection failed"), "{}", error);
+        assert!(!handle.is_running());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn start_reports_a_port_in_use() {
+        let dir = std::env::temp_dir().join(format!("server_tray_port_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("server_tray.sqlite");
+        let taken = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let config = test_config(&dir, &db_path, taken.local_addr().unwrap());
+        let handle = ServerHandle::new(config, CardStore::new(), EventBus::new());
+
+        let error = handle.start().unwrap_err();
+        assert!(error.starts_with("Cannot listen on"), "{}", error);
+        assert!(!handle.is_running());
+        assert!(!ServerLock::is_held(&db_path.to_string_lossy()).unwrap());
+        drop(taken);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+use crate::crypto::write_private;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use ed25519_dalek::{Signer as _, SigningKey};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use warp::{Filter, Rejection, Reply, http::header::CONTENT_TYPE};
+
+/// Response header carrying the detached JWS of the response body.
+pub const JWS_HEADER: &str = "x-server-tray-jws";
+
+/// `SIGNING_KEY_FILE`, default `signing.key` next to the executable.
+pub fn key_path_from_env(default_dir: &Path) -> PathBuf {
+    std::env::var("SIGNING_KEY_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| default_dir.join("signing.key"))
+}
+
+/// Ed25519 key used to sign card data as JWS (`alg: EdDSA`).
+#[derive(Clone)]
+pub struct Signer {
+    key: Arc<SigningKey>,
+    kid: String,
+}
+
+impl Signer {
+    /// Loads the key, generating it on first run. The file holds the base64url
+    /// 32-byte seed.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let key = match fs::read_to_string(path) {
+            Ok(s) => {
+                let seed: [u8; 32] = URL_SAFE_NO_PAD
+                    .decode(s.trim())
+                    .ok()
+                    .and_then(|b| b.try_into().ok())
+                    .ok_or_else(|| format!("{}: not a base64 Ed25519 seed", path.display()))?;
+                SigningKey::from_bytes(&seed)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = SigningKey::from_bytes(&rand::random::<[u8; 32]>());
+                write_private(path, &URL_SAFE_NO_PAD.encode(key.to_bytes()))
+                    .map_err(|e| format!("write {}: {}", path.display(), e))?;
+                key
+            }
+            Err(e) => return Err(format!("read {}: {}", path.display(), e)),
+        };
+        let digest = Sha256::digest(key.verifying_key().as_bytes());
+        Ok(Self {
+            kid: hex::encode(&digest[..8]),
+            key: Arc::new(key),
+        })
+    }
+
+    /// Signs `payload`. The protected header carries `iat` (unix seconds) and
+    /// a random `nonce` so that verifiers can reject stale or replayed data.
+    pub fn sign(&self, payload: &[u8]) -> Jws {
+        let header = serde_json::json!({
+            "alg": "EdDSA",
+            "kid": self.kid,
+            "iat": chrono::Utc::now().timestamp(),
+            "nonce": hex::encode(rand::random::<[u8; 16]>()),
+        });
+        let header = URL_SAFE_NO_PAD.encode(header.to_string());
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let signature = self.key.sign(format!("{}.{}", header, payload).as_bytes());
+        Jws {
+            header,
+            payload,
+            signature: URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        }
+    }
+
+    /// The public key as a JWK set.
+    pub fn jwks(&self) -> serde_json::Value {
+        serde_json::json!({
+            "keys": [{
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": self.kid,
+                "x": URL_SAFE_NO_PAD.encode(self.key.verifying_key().as_bytes()),
+            }]
+        })
+    }
+}
+
+pub struct Jws {
+    header: String,
+    payload: String,
+    signature: String,
+}
+
+impl Jws {
+    /// `header.payload.signature`
+    pub fn compact(&self) -> String {
+        format!("{}.{}.{}", self.header, self.payload, self.signature)
+    }
+
+    /// `header..signature`; the payload travels separately, e.g. as the
+    /// response body.
+    pub fn detached(&self) -> String {
+        format!("{}..{}", self.header, self.signature)
+    }
+}
+
+/// Replies with `body` and its detached signature in `JWS_HEADER`.
+pub fn signed_reply(signer: &Signer, body: Vec<u8>, content_type: &'static str) -> warp::reply::Response {
+    let jws = signer.sign(&body).detached();
+    let mut res = warp::reply::Response::new(body.into());
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(JWS_HEADER, jws.parse().unwrap());
+    res
+}
+
+pub fn signed_json<T: serde::Serialize>(signer: &Signer, body: &T) -> warp::reply::Response {
+    signed_reply(signer, serde_json::to_vec(body).unwrap_or_default(), "application/json")
+}
+
+/// `GET signing/jwks`
+pub fn routes(signer: Signer) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("signing" / "jwks")
+        .and(warp::get())
+        .map(move || warp::reply::json(&signer.jwks()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    #[test]
+    fn detached_signature_verifies_against_the_jwk() {
+        let path = std::env::temp_dir().join(format!("server_tray_signing_{}.key", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let signer = Signer::load(&path).unwrap();
+        let body = br#"{"cid":"1101700203451"}"#;
+
+        let detached = signer.sign(body).detached();
+        let (header, signature) = detached.split_once("..").unwrap();
+        let protected: serde_json::Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).unwrap()).unwrap();
+        assert_eq!(protected["alg"], "EdDSA");
+        assert_eq!(protected["kid"], signer.jwks()["keys"][0]["kid"]);
+        assert!(protected["iat"].is_i64());
+        assert_eq!(protected["nonce"].as_str().unwrap().len(), 32);
+
+        let x = signer.jwks()["keys"][0]["x"].as_str().unwrap().to_string();
+        let public: [u8; 32] = URL_SAFE_NO_PAD.decode(x).unwrap().try_into().unwrap();
+        let public = VerifyingKey::from_bytes(&public).unwrap();
+        let signature: [u8; 64] = URL_SAFE_NO_PAD.decode(signature).unwrap().try_into().unwrap();
+        let signature = Signature::from_bytes(&signature);
+        let signed = |payload: &[u8]| format!("{}.{}", header, URL_SAFE_NO_PAD.encode(payload));
+        assert!(public.verify(signed(body).as_bytes(), &signature).is_ok());
+        assert!(public.verify(signed(b"{}").as_bytes(), &signature).is_err());
+
+        // The key persists, and every signature has a fresh nonce
+        let again = Signer::load(&path).unwrap();
+        assert_eq!(again.jwks(), signer.jwks());
+        assert_ne!(signer.sign(body).compact(), signer.sign(body).compact());
+        fs::remove_file(&path).unwrap();
+    }
+}
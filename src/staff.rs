@@ -0,0 +1,844 @@
+use crate::auth::{Auth, Principal, Scope};
+use crate::card::CardStore;
+use crate::crypto::DataCipher;
+use crate::error::ApiError;
+use crate::events::{CardEvent, EventBus};
+use crate::log;
+use crate::origins::OriginRegistry;
+use crate::users::{self, session, user, user::Role};
+use chrono::{DateTime, Local, Utc};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+    sea_query::Expr,
+};
+use sea_orm_migration::{
+    prelude::{Iden, MigrationName, MigrationTrait, SchemaManager, Table},
+    sea_query,
+    sea_query::ColumnDef,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use warp::{
+    Filter, Rejection, Reply,
+    http::{StatusCode, header},
+};
+
+/// Wrong PINs in a row that lock a user's PIN, for `PIN_LOCKOUT_SECS` the
+/// first time and twice as long each time after that.
+const MAX_PIN_ATTEMPTS: i32 = 3;
+const PIN_LOCKOUT_SECS: i64 = 300;
+
+/// A lost manager or admin card would otherwise sign anyone in with those
+/// rights, so their cards only work with a PIN.
+fn needs_pin(role: Role) -> bool {
+    role != Role::Cashier
+}
+
+fn can_tap(user: &user::Model) -> bool {
+    user.pin_hash.is_some() || !needs_pin(user.role)
+}
+
+/// `STAFF_READER` limits staff logins to the counter reader (any reader when
+/// unset); a tapped card can be claimed for `STAFF_TAP_SECONDS` (default 30).
+#[derive(Clone, Debug)]
+pub struct StaffConfig {
+    pub reader: Option<String>,
+    pub tap_ttl: Duration,
+}
+
+impl StaffConfig {
+    pub fn from_env() -> Self {
+        Self {
+            reader: std::env::var("STAFF_READER").ok().filter(|r| !r.is_empty()),
+            tap_ttl: Duration::from_secs(
+                std::env::var("STAFF_TAP_SECONDS")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(30),
+            ),
+        }
+    }
+
+    fn is_staff_reader(&self, reader: &str) -> bool {
+        self.reader.as_deref().is_none_or(|r| r == reader)
+    }
+}
+
+// Audit trail of operator changes made with a staff card
+pub mod operator_switch {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, serde::Serialize, serde::Deserialize)]
+    #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(16))")]
+    #[serde(rename_all = "snake_case")]
+    pub enum Outcome {
+        #[sea_orm(string_value = "switched")]
+        Switched,
+        #[sea_orm(string_value = "pin_rejected")]
+        PinRejected,
+    }
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "operator_switches")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub at: DateTimeUtc,
+        pub reader: String,
+        /// Operator of the browser session before the switch, if any.
+        pub from_user_id: Option<i32>,
+        pub to_user_id: i32,
+        pub outcome: Outcome,
+        pub remote_addr: Option<String>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+use operator_switch::Outcome;
+
+// Migration
+pub struct StaffCardMigration;
+
+impl MigrationName for StaffCardMigration {
+    fn name(&self) -> &str {
+        "m0009_staff_cards"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for StaffCardMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        if !manager.has_column("users", "cid_digest").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::CidDigest).string())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        if !manager.has_column("users", "pin_hash").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::PinHash).string())
+                        .to_owned(),
+                )
+                .await?;
+        }
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(OperatorSwitches::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(OperatorSwitches::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(OperatorSwitches::At).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(OperatorSwitches::Reader).string().not_null())
+                    .col(ColumnDef::new(OperatorSwitches::FromUserId).integer())
+                    .col(ColumnDef::new(OperatorSwitches::ToUserId).integer().not_null())
+                    .col(ColumnDef::new(OperatorSwitches::Outcome).string_len(16).not_null())
+                    .col(ColumnDef::new(OperatorSwitches::RemoteAddr).string())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_table(Table::drop().table(OperatorSwitches::Table).to_owned())
+            .await?;
+        for column in [Users::PinHash, Users::CidDigest] {
+            manager
+                .alter_table(Table::alter().table(Users::Table).drop_column(column).to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+// Wrong PINs are counted per user, so that inserting the card again does not
+// reset them
+pub struct StaffPinLockoutMigration;
+
+impl MigrationName for StaffPinLockoutMigration {
+    fn name(&self) -> &str {
+        "m0011_staff_pin_lockout"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for StaffPinLockoutMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        if !manager.has_column("users", "pin_failures").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::PinFailures).integer().not_null().default(0))
+                        .to_owned(),
+                )
+                .await?;
+        }
+        if !manager.has_column("users", "pin_locked_until").await? {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(Users::Table)
+                        .add_column(ColumnDef::new(Users::PinLockedUntil).timestamp_with_time_zone())
+                        .to_owned(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        for column in [Users::PinLockedUntil, Users::PinFailures] {
+            manager
+                .alter_table(Table::alter().table(Users::Table).drop_column(column).to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Iden)]
+enum Users {
+    Table,
+    CidDigest,
+    PinHash,
+    PinFailures,
+    PinLockedUntil,
+}
+
+#[derive(Iden)]
+enum OperatorSwitches {
+    Table,
+    Id,
+    At,
+    Reader,
+    FromUserId,
+    ToUserId,
+    Outcome,
+    RemoteAddr,
+}
+
+struct Tap {
+    user: user::Model,
+    reader: String,
+    at: Instant,
+}
+
+enum Claim {
+    Nothing,
+    /// `locked` when this wrong PIN locked the user's PIN.
+    PinRejected { user: user::Model, reader: String, locked: bool },
+    PinLocked { until: DateTime<Utc> },
+    Accepted { user: user::Model, reader: String },
+}
+
+/// The last staff card read on the counter reader, waiting to be claimed by
+/// the browser at the counter.
+#[derive(Clone, Default)]
+pub struct Taps {
+    pending: Arc<Mutex<Option<Tap>>>,
+}
+
+impl Taps {
+    fn set(&self, tap: Tap) {
+        *self.pending.lock().unwrap() = Some(tap);
+    }
+
+    /// The user, reader and read time of the tap waiting to be claimed.
+    fn waiting(&self, ttl: Duration) -> Option<(i32, String, Instant)> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.as_ref().is_some_and(|t| t.at.elapsed() >= ttl) {
+            *pending = None;
+        }
+        pending.as_ref().map(|t| (t.user.id, t.reader.clone(), t.at))
+    }
+
+    /// Removes the tap read at `at`, unless it is gone or a newer card
+    /// replaced it.
+    fn take(&self, at: Instant) -> Option<Tap> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.as_ref().is_some_and(|t| t.at == at) {
+            pending.take()
+        } else {
+            None
+        }
+    }
+
+    /// Checks the PIN against the stored user, whose wrong PINs count across
+    /// taps, then consumes the tap so that it is claimed only once. Argon2
+    /// runs on a blocking thread, outside the lock.
+    async fn claim(&self, db: &DatabaseConnection, pin: Option<&str>, ttl: Duration) -> Result<Claim, ApiError> {
+        let Some((id, reader, at)) = self.waiting(ttl) else {
+            return Ok(Claim::Nothing);
+        };
+        let Some(user) = user::Entity::find_by_id(id).one(db).await?.filter(can_tap) else {
+            self.take(at);
+            return Ok(Claim::Nothing);
+        };
+        if let Some(pin_hash) = user.pin_hash.clone() {
+            if let Some(until) = user.pin_locked_until.filter(|until| *until > Utc::now()) {
+                self.take(at);
+                return Ok(Claim::PinLocked { until });
+            }
+            let pin = pin.unwrap_or_default().to_string();
+            let valid = tokio::task::spawn_blocking(move || users::verify_password(&pin, &pin_hash))
+                .await
+                .map_err(|e| ApiError::Internal(e.to_string()))?;
+            if !valid {
+                let locked = record_pin_failure(db, user.id).await?;
+                if locked {
+                    self.take(at);
+                }
+                return Ok(Claim::PinRejected { user, reader, locked });
+            }
+            if user.pin_failures > 0 {
+                reset_pin_failures(db, user.id).await?;
+            }
+        }
+        Ok(match self.take(at) {
+            Some(tap) => Claim::Accepted { user, reader: tap.reader },
+            None => Claim::Nothing,
+        })
+    }
+
+    fn clear_reader(&self, reader: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.as_ref().is_some_and(|t| t.reader == reader) {
+            *pending = None;
+        }
+    }
+}
+
+/// Watches the counter reader for cards linked to a user.
+pub fn spawn(db: DatabaseConnection, bus: EventBus, cipher: DataCipher, config: StaffConfig, taps: Taps) {
+    tokio::spawn(async move {
+        let mut rx = bus.subscribe();
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            };
+            match &event.event {
+                CardEvent::ReadCompleted { reader, card } if config.is_staff_reader(reader) => {
+                    let found = user::Entity::find()
+                        .filter(user::Column::CidDigest.eq(cipher.cid_digest(&card.cid)))
+                        .one(&db)
+                        .await;
+                    match found {
+                        Ok(Some(user)) if !can_tap(&user) => {
+                            log::write_log_line(&format!(
+                                "Staff card of {} ignored on {}: {} cards need a PIN",
+                                user.username,
+                                reader,
+                                user.role.as_str()
+                            ));
+                        }
+                        Ok(Some(user)) => {
+                            log::write_log_line(&format!("Staff card of {} read on {}", user.username, reader));
+                            taps.set(Tap {
+                                user,
+                                reader: reader.clone(),
+                                at: Instant::now(),
+                            });
+                        }
+                        Ok(None) => {}
+                        Err(e) => log::write_log_line(&format!("Staff card lookup failed: {}", e)),
+                    }
+                }
+                // Removing the card withdraws a tap nobody claimed yet.
+                CardEvent::CardRemoved { reader } => taps.clear_reader(reader),
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Counts a wrong PIN and returns whether it locked the user's PIN.
+async fn record_pin_failure(db: &DatabaseConnection, id: i32) -> Result<bool, DbErr> {
+    user::Entity::update_many()
+        .col_expr(user::Column::PinFailures, Expr::col(user::Column::PinFailures).add(1))
+        .filter(user::Column::Id.eq(id))
+        .exec(db)
+        .await?;
+    let failures = user::Entity::find_by_id(id).one(db).await?.map_or(0, |u| u.pin_failures);
+    if failures == 0 || failures % MAX_PIN_ATTEMPTS != 0 {
+        return Ok(false);
+    }
+    let lockout = PIN_LOCKOUT_SECS << (failures / MAX_PIN_ATTEMPTS - 1).clamp(0, 8);
+    user::Entity::update_many()
+        .col_expr(
+            user::Column::PinLockedUntil,
+            Expr::value(Some(Utc::now() + chrono::Duration::seconds(lockout))),
+        )
+        .filter(user::Column::Id.eq(id))
+        .exec(db)
+        .await?;
+    Ok(true)
+}
+
+async fn reset_pin_failures(db: &DatabaseConnection, id: i32) -> Result<(), DbErr> {
+    user::Entity::update_many()
+        .col_expr(user::Column::PinFailures, Expr::value(0))
+        .col_expr(user::Column::PinLockedUntil, Expr::value(Option::<DateTime<Utc>>::None))
+        .filter(user::Column::Id.eq(id))
+        .exec(db)
+        .await?;
+    Ok(())
+}
+
+async fn audit(
+    db: &DatabaseConnection,
+    reader: &str,
+    from: Option<&user::Model>,
+    to: &user::Model,
+    outcome: Outcome,
+    remote: Option<SocketAddr>,
+) {
+    let row = operator_switch::ActiveModel {
+        at: Set(Utc::now()),
+        reader: Set(reader.to_string()),
+        from_user_id: Set(from.map(|u| u.id)),
+        to_user_id: Set(to.id),
+        outcome: Set(outcome),
+        remote_addr: Set(remote.map(|a| a.ip().to_string())),
+        ..Default::default()
+    };
+    if let Err(e) = row.insert(db).await {
+        log::write_log_line(&format!("Operator switch audit failed: {}", e));
+    }
+    let from = from.map(|u| u.username.as_str()).unwrap_or("nobody");
+    let outcome = match outcome {
+        Outcome::Switched => "switched",
+        Outcome::PinRejected => "PIN rejected",
+    };
+    log::write_log_line(&format!("Operator {} -> {} on {}: {}", from, to.username, reader, outcome));
+}
+
+#[derive(Clone)]
+struct Staff {
+    db: DatabaseConnection,
+    cipher: DataCipher,
+    cards: CardStore,
+    config: StaffConfig,
+    taps: Taps,
+    secure_cookies: bool,
+}
+
+#[derive(serde::Serialize)]
+struct TapStatus<'a> {
+    pending: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+    pin_required: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct TapClaim {
+    pin: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CardLink {
+    /// Taken from the card in the counter reader when omitted.
+    cid: Option<String>,
+    /// 4 to 8 digits; `null` or omitted means no PIN.
+    pin: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct AuditQuery {
+    limit: Option<u64>,
+}
+
+/// `api/staff/tap` for the counter browser, card links under `api/users` and
+/// the `api/staff/switches` audit log.
+pub fn routes(
+    db: DatabaseConnection,
+    cipher: DataCipher,
+    cards: CardStore,
+    config: StaffConfig,
+    taps: Taps,
+    origins: OriginRegistry,
+    auth: Auth,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let staff = Staff {
+        db,
+        cipher,
+        cards,
+        config,
+        taps,
+        secure_cookies: auth.secure_cookies(),
+    };
+    let staff_filter = warp::any().map(move || staff.clone());
+    let admin = auth.require(Scope::Admin);
+
+    let counter = counter_page(origins);
+
+    let tap = warp::path!("staff" / "tap")
+        .and(warp::get())
+        .and(counter.clone())
+        .and(staff_filter.clone())
+        .and_then(get_tap);
+
+    let claim = warp::path!("staff" / "tap")
+        .and(warp::post())
+        .and(counter)
+        .and(warp::addr::remote())
+        .and(auth.principal())
+        .and(warp::body::json())
+        .and(staff_filter.clone())
+        .and_then(post_tap);
+
+    let switches = warp::path!("staff" / "switches")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(warp::query::<AuditQuery>())
+        .and(staff_filter.clone())
+        .and_then(list_switches);
+
+    let link = warp::path!("users" / i32 / "card")
+        .and(warp::put())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(staff_filter.clone())
+        .and_then(link_card);
+
+    let unlink = warp::path!("users" / i32 / "card")
+        .and(warp::delete())
+        .and(admin)
+        .and(staff_filter)
+        .and_then(unlink_card);
+
+    tap.or(claim).or(switches).or(link).or(unlink)
+}
+
+fn is_local(remote: Option<SocketAddr>) -> bool {
+    remote.is_some_and(|addr| addr.ip().is_loopback())
+}
+
+/// Passes only this app's login and home pages on this machine: from
+/// loopback, without a foreign `Origin`, and echoing the CSRF cookie (which
+/// the login page sets for visitors) in `X-CSRF-Token`.
+fn counter_page(origins: OriginRegistry) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::cookie::optional::<String>(users::CSRF_COOKIE))
+        .and(warp::header::optional::<String>(users::CSRF_HEADER))
+        .and_then(
+            move |remote: Option<SocketAddr>, origin: Option<String>, cookie: Option<String>, csrf: Option<String>| {
+                let own = origin.as_deref().is_none_or(|o| origins.is_own(o));
+                async move {
+                    if !is_local(remote) || !own {
+                        return Err(Rejection::from(ApiError::Forbidden(
+                            "card login is only available on this machine".to_string(),
+                        )));
+                    }
+                    if cookie.is_none() || cookie != csrf {
+                        return Err(ApiError::Forbidden("CSRF token missing or invalid".to_string()).into());
+                    }
+                    Ok(())
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Polled by the login and home pages at the counter.
+async fn get_tap(staff: Staff) -> Result<impl Reply, Rejection> {
+    let pending = staff.taps.pending.lock().unwrap();
+    let tap = pending.as_ref().filter(|t| t.at.elapsed() < staff.config.tap_ttl);
+    let status = TapStatus {
+        pending: tap.is_some(),
+        username: tap.map(|t| t.user.username.as_str()),
+        pin_required: tap.is_some_and(|t| t.user.pin_hash.is_some()),
+    };
+    Ok(warp::reply::json(&status))
+}
+
+/// Signs the tapped staff member in, replacing the caller's session.
+async fn post_tap(
+    remote: Option<SocketAddr>,
+    principal: Principal,
+    claim: TapClaim,
+    staff: Staff,
+) -> Result<impl Reply, Rejection> {
+    let from = match &principal {
+        Principal::User(signed_in) => Some(signed_in.clone()),
+        _ => None,
+    };
+
+    let claimed = staff
+        .taps
+        .claim(&staff.db, claim.pin.as_deref(), staff.config.tap_ttl)
+        .await?;
+    let (user, reader) = match claimed {
+        Claim::Nothing => return Err(ApiError::NotFound("no staff card waiting".to_string()).into()),
+        Claim::PinLocked { until } => {
+            let message = format!(
+                "too many wrong PINs; try again after {}",
+                until.with_timezone(&Local).format("%H:%M")
+            );
+            return Err(ApiError::Forbidden(message).into());
+        }
+        Claim::PinRejected { user, reader, locked } => {
+            audit(&staff.db, &reader, from.as_ref().map(|s| &s.user), &user, Outcome::PinRejected, remote).await;
+            let message = if locked {
+                "wrong PIN; the PIN is locked for now"
+            } else {
+                "wrong PIN"
+            };
+            return Err(ApiError::Forbidden(message.to_string()).into());
+        }
+        Claim::Accepted { user, reader } => (user, reader),
+    };
+
+    if let Some(from) = &from {
+        session::Entity::delete_by_id(from.session.id)
+            .exec(&staff.db)
+            .await
+            .map_err(ApiError::from)?;
+    }
+    let (session, token) = users::open_session(&staff.db, user.id)
+        .await
+        .map_err(ApiError::from)?;
+    audit(&staff.db, &reader, from.as_ref().map(|s| &s.user), &user, Outcome::Switched, remote).await;
+
+    let mut res = warp::reply::json(&user).into_response();
+    for cookie in users::session_cookies(&token, &session.csrf_token, session.expires_at, staff.secure_cookies) {
+        if let Ok(value) = cookie.parse() {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    Ok(res)
+}
+
+async fn list_switches(query: AuditQuery, staff: Staff) -> Result<impl Reply, Rejection> {
+    let list = operator_switch::Entity::find()
+        .order_by_desc(operator_switch::Column::Id)
+        .limit(query.limit.unwrap_or(100).min(1000))
+        .all(&staff.db)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&list))
+}
+
+fn is_valid_pin(pin: &str) -> bool {
+    (4..=8).contains(&pin.len()) && pin.bytes().all(|b| b.is_ascii_digit())
+}
+
+async fn link_card(id: i32, link: CardLink, staff: Staff) -> Result<impl Reply, Rejection> {
+    let cid = match link.cid {
+        Some(cid) => cid,
+        None => {
+            let status = match &staff.config.reader {
+                Some(reader) => staff.cards.reader(reader),
+                None => staff.cards.current(),
+            };
+            match status.and_then(|s| s.card) {
+                Some(read) => read.info.cid,
+                None => {
+                    return Err(ApiError::BadRequest("no cid given and no card in the reader".to_string()).into());
+                }
+            }
+        }
+    };
+    if cid.len() != 13 || !cid.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ApiError::BadRequest("cid must be 13 digits".to_string()).into());
+    }
+    if link.pin.as_deref().is_some_and(|p| !is_valid_pin(p)) {
+        return Err(ApiError::BadRequest("pin must be 4 to 8 digits".to_string()).into());
+    }
+    let holder = user::Entity::find_by_id(id)
+        .one(&staff.db)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or_else(|| ApiError::NotFound("user not found".to_string()))?;
+    if link.pin.is_none() && needs_pin(holder.role) {
+        return Err(ApiError::BadRequest(format!("{} cards need a pin", holder.role.as_str())).into());
+    }
+    let pin_hash = link
+        .pin
+        .as_deref()
+        .map(users::hash_password)
+        .transpose()
+        .map_err(ApiError::Internal)?;
+
+    if !set_card(&staff.db, id, Some(staff.cipher.cid_digest(&cid)), pin_hash)
+        .await
+        .map_err(ApiError::from)?
+    {
+        return Err(ApiError::NotFound("user not found".to_string()).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unlink_card(id: i32, staff: Staff) -> Result<impl Reply, Rejection> {
+    if !set_card(&staff.db, id, None, None).await.map_err(ApiError::from)? {
+        return Err(ApiError::NotFound("user not found".to_string()).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// A card links to one user at most; linking it again moves it.
+async fn set_card(
+    db: &DatabaseConnection,
+    id: i32,
+    cid_digest: Option<String>,
+    pin_hash: Option<String>,
+) -> Result<bool, DbErr> {
+    if let Some(digest) = &cid_digest {
+        user::Entity::update_many()
+            .col_expr(user::Column::CidDigest, Expr::value(Option::<String>::None))
+            .col_expr(user::Column::PinHash, Expr::value(Option::<String>::None))
+            .filter(user::Column::CidDigest.eq(digest.as_str()))
+            .filter(user::Column::Id.ne(id))
+            .exec(db)
+            .await?;
+    }
+    let res = user::Entity::update_many()
+        .col_expr(user::Column::CidDigest, Expr::value(cid_digest))
+        .col_expr(user::Column::PinHash, Expr::value(pin_hash))
+        .col_expr(user::Column::PinFailures, Expr::value(0))
+        .col_expr(user::Column::PinLockedUntil, Expr::value(Option::<DateTime<Utc>>::None))
+        .filter(user::Column::Id.eq(id))
+        .exec(db)
+        .await?;
+    Ok(res.rows_affected > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migrator;
+    use crate::origins::OriginPolicy;
+
+    fn staff_member(role: Role, pin: Option<&str>) -> user::Model {
+        user::Model {
+            id: 1,
+            username: "somchai".to_string(),
+            password_hash: String::new(),
+            role,
+            created_at: Utc::now(),
+            cid_digest: Some("digest".to_string()),
+            pin_hash: pin.map(|p| users::hash_password(p).unwrap()),
+            pin_failures: 0,
+            pin_locked_until: None,
+        }
+    }
+
+    #[test]
+    fn only_cashier_cards_work_without_a_pin() {
+        assert!(can_tap(&staff_member(Role::Cashier, None)));
+        assert!(!can_tap(&staff_member(Role::Manager, None)));
+        assert!(!can_tap(&staff_member(Role::Admin, None)));
+        assert!(can_tap(&staff_member(Role::Manager, Some("2580"))));
+        assert!(can_tap(&staff_member(Role::Admin, Some("2580"))));
+    }
+
+    #[tokio::test]
+    async fn wrong_pins_lock_the_pin_across_taps() {
+        let db = migrator::test_db().await;
+        let admin = users::create_user(&db, "somchai", "correct horse", Role::Admin).await.unwrap();
+        let pin_hash = users::hash_password("2580").unwrap();
+        set_card(&db, admin.id, Some("digest".to_string()), Some(pin_hash)).await.unwrap();
+        let taps = Taps::default();
+        let ttl = Duration::from_secs(30);
+        let insert = || {
+            taps.set(Tap {
+                user: admin.clone(),
+                reader: "counter".to_string(),
+                at: Instant::now(),
+            })
+        };
+        let claim = |pin: Option<&'static str>| taps.claim(&db, pin, ttl);
+
+        insert();
+        assert!(matches!(claim(None).await.unwrap(), Claim::PinRejected { locked: false, .. }));
+        assert!(matches!(claim(Some("0000")).await.unwrap(), Claim::PinRejected { locked: false, .. }));
+        // Inserting the card again does not start the count over
+        insert();
+        assert!(matches!(claim(Some("1111")).await.unwrap(), Claim::PinRejected { locked: true, .. }));
+        assert!(matches!(claim(Some("2580")).await.unwrap(), Claim::Nothing));
+        insert();
+        assert!(matches!(claim(Some("2580")).await.unwrap(), Claim::PinLocked { .. }));
+
+        user::Entity::update_many()
+            .col_expr(user::Column::PinLockedUntil, Expr::value(Some(Utc::now())))
+            .exec(&db)
+            .await
+            .unwrap();
+        insert();
+        assert!(matches!(claim(Some("2580")).await.unwrap(), Claim::Accepted { .. }));
+        assert!(matches!(claim(Some("2580")).await.unwrap(), Claim::Nothing));
+        let stored = user::Entity::find_by_id(admin.id).one(&db).await.unwrap().unwrap();
+        assert_eq!((stored.pin_failures, stored.pin_locked_until), (0, None));
+    }
+
+    #[tokio::test]
+    async fn only_the_counter_page_sees_taps() {
+        let policy = OriginPolicy {
+            allowlist: vec!["https://shop.example".to_string()],
+            consent: false,
+            hosts: Vec::new(),
+        };
+        let own = vec!["http://127.0.0.1:8080".to_string()];
+        let origins = OriginRegistry::load(migrator::test_db().await, policy, own, None).await.unwrap();
+        let counter = counter_page(origins);
+        let cookie = format!("{}=abc", users::CSRF_COOKIE);
+        let request = |remote: [u8; 4]| warp::test::request().remote_addr((remote, 40000).into());
+        let local = || request([127, 0, 0, 1]);
+
+        assert!(local().header("cookie", &cookie).header(users::CSRF_HEADER, "abc").matches(&counter).await);
+        assert!(
+            local()
+                .header("cookie", &cookie)
+                .header(users::CSRF_HEADER, "abc")
+                .header("origin", "http://127.0.0.1:8080")
+                .matches(&counter)
+                .await
+        );
+
+        assert!(!local().header("cookie", &cookie).matches(&counter).await);
+        assert!(!local().header(users::CSRF_HEADER, "abc").matches(&counter).await);
+        assert!(!local().header("cookie", &cookie).header(users::CSRF_HEADER, "abd").matches(&counter).await);
+        // Approved for the API, but not a page of this app
+        assert!(
+            !local()
+                .header("cookie", &cookie)
+                .header(users::CSRF_HEADER, "abc")
+                .header("origin", "https://shop.example")
+                .matches(&counter)
+                .await
+        );
+        assert!(
+            !request([192, 168, 1, 5])
+                .header("cookie", &cookie)
+                .header(users::CSRF_HEADER, "abc")
+                .matches(&counter)
+                .await
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use chrono::{Datelike, NaiveDate};
+use std::fmt;
+
+// Card dates are `YYYYMMDD` in the Buddhist era. Month and day are `00` (or
+// `--` on some cards) when the registry does not know them, and a lifelong
+// card expires `99999999`.
+const BE_OFFSET: i32 = 543;
+const LIFELONG: &str = "99999999";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DateError {
+    Empty,
+    Malformed(String),
+}
+
+impl fmt::Display for DateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DateError::Empty => write!(f, "date not present on card"),
+            DateError::Malformed(s) => write!(f, "unrecognised card date {:?}", s),
+        }
+    }
+}
+
+impl std::error::Error for DateError {}
+
+/// Birth date with the Gregorian year; month and day may be unknown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BirthDate {
+    pub year: i32,
+    pub month: Option<u32>,
+    pub day: Option<u32>,
+}
+
+impl BirthDate {
+    pub fn parse(s: &str) -> Result<Self, DateError> {
+        let (year, month, day) = split_digits(s)?;
+        let malformed = || DateError::Malformed(s.to_string());
+        match (month, day) {
+            (0, 0) => Ok(Self { year, month: None, day: None }),
+            (1..=12, 0) => Ok(Self { year, month: Some(month), day: None }),
+            (_, 0) | (0, _) => Err(malformed()),
+            _ => {
+                NaiveDate::from_ymd_opt(year, month, day).ok_or_else(malformed)?;
+                Ok(Self { year, month: Some(month), day: Some(day) })
+            }
+        }
+    }
+
+    /// Completed years on `today`. An unknown month or day is taken as the
+    /// latest possible one, so the age is never overstated.
+    pub fn age_on(&self, today: NaiveDate) -> i32 {
+        let month = self.month.unwrap_or(12);
+        let day = self.day.unwrap_or_else(|| last_day_of_month(self.year, month));
+        let mut age = today.year() - self.year;
+        if (today.month(), today.day()) < (month, day) {
+            age -= 1;
+        }
+        age
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpiryDate {
+    On(NaiveDate),
+    Lifelong,
+}
+
+impl ExpiryDate {
+    pub fn parse(s: &str) -> Result<Self, DateError> {
+        if s == LIFELONG {
+            return Ok(ExpiryDate::Lifelong);
+        }
+        let (year, month, day) = split_digits(s)?;
+        NaiveDate::from_ymd_opt(year, month, day)
+            .map(ExpiryDate::On)
+            .ok_or_else(|| DateError::Malformed(s.to_string()))
+    }
+
+    /// A card is valid through its expiry date.
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        match self {
+            ExpiryDate::On(date) => today > *date,
+            ExpiryDate::Lifelong => false,
+        }
+    }
+}
+
+/// Splits `YYYYMMDD` (Buddhist era) into a Gregorian year, month and day.
+fn split_digits(s: &str) -> Result<(i32, u32, u32), DateError> {
+    if s.is_empty() {
+        return Err(DateError::Empty);
+    }
+    let malformed = || DateError::Malformed(s.to_string());
+    let number = |part: &str| -> Option<u32> {
+        match part {
+            "--" => Some(0),
+            _ if part.bytes().all(|b| b.is_ascii_digit()) => part.parse().ok(),
+            _ => None,
+        }
+    };
+    if s.len() != 8 || !s.is_char_boundary(4) || !s.is_char_boundary(6) {
+        return Err(malformed());
+    }
+    let year = number(&s[..4]).ok_or_else(malformed)? as i32;
+    let month = number(&s[4..6]).ok_or_else(malformed)?;
+    let day = number(&s[6..]).ok_or_else(malformed)?;
+    if year <= BE_OFFSET {
+        return Err(malformed());
+    }
+    Ok((year - BE_OFFSET, month, day))
+}
+
+fn last_day_of_month(year: i32, month: u32) -> u32 {
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    NaiveDate::from_ymd_opt(next_year, next_month, 1)
+        .and_then(|d| d.pred_opt())
+        .map(|d| d.day())
+        .unwrap_or(28)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn parses_buddhist_era_birth_dates() {
+        let born = BirthDate::parse("25330415").unwrap();
+        assert_eq!(born, BirthDate { year: 1990, month: Some(4), day: Some(15) });
+        assert_eq!(BirthDate::parse(""), Err(DateError::Empty));
+        for bad in ["2533041", "2533-4-15", "25331315", "25330431", "05000101", "2533041x"] {
+            assert_eq!(BirthDate::parse(bad), Err(DateError::Malformed(bad.to_string())), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn reads_unknown_month_and_day() {
+        let year_only = BirthDate { year: 1990, month: None, day: None };
+        assert_eq!(BirthDate::parse("25330000"), Ok(year_only));
+        assert_eq!(BirthDate::parse("2533----"), Ok(year_only));
+        assert_eq!(
+            BirthDate::parse("253304--"),
+            Ok(BirthDate { year: 1990, month: Some(4), day: None })
+        );
+        assert!(BirthDate::parse("25330015").is_err());
+        assert!(BirthDate::parse("2533--15").is_err());
+    }
+
+    #[test]
+    fn counts_completed_years() {
+        let born = BirthDate::parse("25330415").unwrap();
+        assert_eq!(born.age_on(date(2010, 4, 14)), 19);
+        assert_eq!(born.age_on(date(2010, 4, 15)), 20);
+    }
+
+    #[test]
+    fn unknown_parts_never_overstate_the_age() {
+        let year_only = BirthDate::parse("25330000").unwrap();
+        assert_eq!(year_only.age_on(date(2010, 12, 30)), 19);
+        assert_eq!(year_only.age_on(date(2010, 12, 31)), 20);
+        let month_only = BirthDate::parse("25330200").unwrap();
+        assert_eq!(month_only.age_on(date(2010, 2, 27)), 19);
+        assert_eq!(month_only.age_on(date(2010, 2, 28)), 20);
+        // 1992 is a leap year, so an unknown February day could be the 29th
+        let leap_month = BirthDate::parse("25350200").unwrap();
+        assert_eq!(leap_month.age_on(date(2012, 2, 28)), 19);
+        assert_eq!(leap_month.age_on(date(2012, 2, 29)), 20);
+    }
+
+    #[test]
+    fn leap_day_birthdays_come_of_age_on_the_first_of_march() {
+        let born = BirthDate::parse("25350229").unwrap();
+        assert_eq!(born.age_on(date(2012, 2, 28)), 19);
+        assert_eq!(born.age_on(date(2012, 2, 29)), 20);
+        assert_eq!(born.age_on(date(2013, 2, 28)), 20);
+        assert_eq!(born.age_on(date(2013, 3, 1)), 21);
+        assert!(BirthDate::parse("25340229").is_err());
+    }
+
+    #[test]
+    fn a_card_is_valid_through_its_expiry_date() {
+        let expiry = ExpiryDate::parse("25700101").unwrap();
+        assert_eq!(expiry, ExpiryDate::On(date(2027, 1, 1)));
+        assert!(!expiry.is_expired(date(2027, 1, 1)));
+        assert!(expiry.is_expired(date(2027, 1, 2)));
+        assert_eq!(ExpiryDate::parse("99999999"), Ok(ExpiryDate::Lifelong));
+        assert!(!ExpiryDate::Lifelong.is_expired(date(2100, 1, 1)));
+        assert!(ExpiryDate::parse("25700000").is_err());
+        assert!(ExpiryDate::parse("2570----").is_err());
+    }
+}
@@ -1,3 +1,4 @@
 pub mod thai_id;
 pub mod apdu;
 pub mod parser;
+pub mod date;
@@ -1,9 +1,40 @@
 use crate::thaiid::apdu::*;
+use crate::thaiid::date::{BirthDate, DateError, ExpiryDate};
 use crate::thaiid::parser::decode_tis620;
-use pcsc::{Context, Scope, ShareMode, Protocols};
+use pcsc::Card;
 use base64::{engine::general_purpose, Engine as _};
 
-#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Cid,
+    ThName,
+    EnName,
+    Birth,
+    Gender,
+    Issuer,
+    IssueDate,
+    ExpireDate,
+    Address,
+    Photo,
+}
+
+impl Field {
+    pub const ALL: [Field; 10] = [
+        Field::Cid,
+        Field::ThName,
+        Field::EnName,
+        Field::Birth,
+        Field::Gender,
+        Field::Issuer,
+        Field::IssueDate,
+        Field::ExpireDate,
+        Field::Address,
+        Field::Photo,
+    ];
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
 pub struct ThaiIdInfo {
     pub cid: String,
     pub th_name: String,
@@ -17,52 +48,75 @@ pub struct ThaiIdInfo {
     pub photo_base64: String,
 }
 
-#[allow(dead_code)]
-pub fn read_thai_id() -> ThaiIdInfo {
-    let ctx = Context::establish(Scope::User).expect("PCSC init failed");
-    let mut reader_buf = [0; 2048];
-    let reader_list = ctx.list_readers(&mut reader_buf).expect("List readers failed");
-    let mut readers = reader_list.clone();
-    let reader_name = readers.next().expect("No reader found");
-    let card = ctx.connect(reader_name, ShareMode::Shared, Protocols::ANY).expect("Card connect failed");
+impl ThaiIdInfo {
+    pub fn birth_date(&self) -> Result<BirthDate, DateError> {
+        BirthDate::parse(&self.birth)
+    }
+
+    pub fn expiry_date(&self) -> Result<ExpiryDate, DateError> {
+        ExpiryDate::parse(&self.expire_date)
+    }
+}
+
+/// Reads the selected fields; fields that are not selected are left empty.
+pub fn read_thai_id(card: &Card, fields: &[Field]) -> Result<ThaiIdInfo, String> {
     let get_response_prefix: &[u8] = &[0x00, 0xC0, 0x00, 0x00];
     let mut rapdu_buf = [0; 512];
 
     let mut select_apdu = Vec::new();
     select_apdu.extend_from_slice(SELECT);
     select_apdu.extend_from_slice(THAI_CARD);
-    card.transmit(&select_apdu, &mut rapdu_buf).expect("SELECT failed");
+    card.transmit(&select_apdu, &mut rapdu_buf)
+        .map_err(|e| format!("SELECT failed: {}", e))?;
 
     macro_rules! read_field {
         ($cmd:expr, $desc:expr) => {{
-            card.transmit($cmd, &mut rapdu_buf).expect(concat!("Send ", $desc, " failed"));
+            card.transmit($cmd, &mut rapdu_buf)
+                .map_err(|e| format!(concat!("Send ", $desc, " failed: {}"), e))?;
             let mut apdu = get_response_prefix.to_vec();
             apdu.push($cmd[$cmd.len() - 1]);
-            let data = card.transmit(&apdu, &mut rapdu_buf).expect(concat!("Recv ", $desc, " failed"));
+            let data = card.transmit(&apdu, &mut rapdu_buf)
+                .map_err(|e| format!(concat!("Recv ", $desc, " failed: {}"), e))?;
             if data.len() < 2 || data[data.len() - 2] != 0x90 || data[data.len() - 1] != 0x00 {
-                panic!("{} response error", $desc);
+                return Err(format!("{} response error", $desc));
             }
             decode_tis620(&data[..data.len() - 2])
         }};
     }
 
-    let cid = read_field!(&CMD_CID, "CID");
-    let th_name = read_field!(&CMD_THFULLNAME, "TH Name");
-    let en_name = read_field!(&CMD_ENFULLNAME, "EN Name");
-    let birth = read_field!(&CMD_BIRTH, "Birth");
-    let gender = read_field!(&CMD_GENDER, "Gender");
-    let issuer = read_field!(&CMD_ISSUER, "Issuer");
-    let issue_date = read_field!(&CMD_ISSUE, "Issue Date");
-    let expire_date = read_field!(&CMD_EXPIRE, "Expire Date");
-    let address = read_field!(&CMD_ADDRESS, "Address");
+    macro_rules! read_selected {
+        ($field:expr, $cmd:expr, $desc:expr) => {{
+            if fields.contains(&$field) {
+                read_field!($cmd, $desc)
+            } else {
+                String::new()
+            }
+        }};
+    }
 
+    let cid = read_selected!(Field::Cid, &CMD_CID, "CID");
+    let th_name = read_selected!(Field::ThName, &CMD_THFULLNAME, "TH Name");
+    let en_name = read_selected!(Field::EnName, &CMD_ENFULLNAME, "EN Name");
+    let birth = read_selected!(Field::Birth, &CMD_BIRTH, "Birth");
+    let gender = read_selected!(Field::Gender, &CMD_GENDER, "Gender");
+    let issuer = read_selected!(Field::Issuer, &CMD_ISSUER, "Issuer");
+    let issue_date = read_selected!(Field::IssueDate, &CMD_ISSUE, "Issue Date");
+    let expire_date = read_selected!(Field::ExpireDate, &CMD_EXPIRE, "Expire Date");
+    let address = read_selected!(Field::Address, &CMD_ADDRESS, "Address");
+
+    let photo_cmds: &[&[u8]] = if fields.contains(&Field::Photo) { &CMD_PHOTOS } else { &[] };
     let mut photo: Vec<u8> = Vec::new();
-    for cmd in CMD_PHOTOS.iter() {
-        card.transmit(cmd, &mut rapdu_buf).expect("Photo command failed");
+    for cmd in photo_cmds.iter() {
+        card.transmit(cmd, &mut rapdu_buf)
+            .map_err(|e| format!("Photo command failed: {}", e))?;
         let mut apdu = get_response_prefix.to_vec();
         apdu.push(cmd[cmd.len() - 1]);
-        let part = card.transmit(&apdu, &mut rapdu_buf).expect("GET RESPONSE for photo failed");
-        if part.len() < 2 || part[part.len() - 2] != 0x90 || part[part.len() - 1] != 0x00 {
+        let part = card.transmit(&apdu, &mut rapdu_buf)
+            .map_err(|e| format!("GET RESPONSE for photo failed: {}", e))?;
+        if part.len() < 2 {
+            return Err("Photo response error".to_string());
+        }
+        if part[part.len() - 2] != 0x90 || part[part.len() - 1] != 0x00 {
             eprintln!("Photo part warning");
         }
         photo.extend_from_slice(&part[..part.len() - 2]);
@@ -70,7 +124,7 @@ pub fn read_thai_id() -> ThaiIdInfo {
 
     let photo_base64 = general_purpose::STANDARD.encode(&photo);
 
-    ThaiIdInfo {
+    Ok(ThaiIdInfo {
         cid,
         th_name,
         en_name,
@@ -81,5 +135,5 @@ pub fn read_thai_id() -> ThaiIdInfo {
         expire_date,
         address,
         photo_base64,
-    }
+    })
 }
@@ -0,0 +1,242 @@
+use crate::crypto::write_private;
+use crate::error::ApiError;
+use crate::log;
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, KeyUsagePurpose, SanType,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+use warp::{Filter, Rejection, Reply};
+
+const CA_DAYS: i64 = 3650;
+// Stays under the 398-day limit browsers apply to leaf certificates.
+const LEAF_DAYS: i64 = 397;
+const RENEW_BEFORE_DAYS: i64 = 30;
+
+const CA_CERT: &str = "ca.pem";
+const CA_KEY: &str = "ca.key";
+const LEAF_CERT: &str = "cert.pem";
+const LEAF_KEY: &str = "key.pem";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsMode {
+    /// Plain HTTP only.
+    Off,
+    /// HTTPS only.
+    Https,
+    /// Plain HTTP on the main address and HTTPS on `port`.
+    Both,
+}
+
+/// `TLS_MODE=off|https|both` (default `off`), `TLS_PORT` (default 8443) and
+/// `TLS_DIR` for the certificates (default `tls` next to the executable).
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub mode: TlsMode,
+    pub port: u16,
+    pub dir: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn from_env(default_dir: &Path) -> Self {
+        let mode = match std::env::var("TLS_MODE").as_deref() {
+            Ok("https") => TlsMode::Https,
+            Ok("both") => TlsMode::Both,
+            _ => TlsMode::Off,
+        };
+        Self {
+            mode,
+            port: std::env::var("TLS_PORT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(8443),
+            dir: std::env::var("TLS_DIR")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| default_dir.join("tls")),
+        }
+    }
+
+    pub fn serves_http(&self) -> bool {
+        self.mode != TlsMode::Https
+    }
+
+    pub fn serves_https(&self) -> bool {
+        self.mode != TlsMode::Off
+    }
+
+    pub fn https_address(&self, address: SocketAddr) -> SocketAddr {
+        SocketAddr::new(address.ip(), self.port)
+    }
+}
+
+/// Leaf certificate chain and key for the HTTPS listener.
+pub struct ServerCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TlsStatus {
+    pub ca_sha256: String,
+    pub ca_not_after: DateTime<Utc>,
+    pub cert_not_after: DateTime<Utc>,
+}
+
+/// Loads the certificates from `dir`, creating the local CA on first run and
+/// issuing a new `localhost`/`127.0.0.1` leaf when it is missing or within
+/// 30 days of expiry.
+pub fn ensure(dir: &Path) -> Result<ServerCert, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("create {}: {}", dir.display(), e))?;
+    let (ca_cert, ca_key) = load_or_create_ca(dir)?;
+    let ca_chain = ca_pem(dir)?;
+
+    let renew_at = Utc::now() + Duration::days(RENEW_BEFORE_DAYS);
+    let current = read(&dir.join(LEAF_CERT))?.zip(read(&dir.join(LEAF_KEY))?);
+    if let Some((cert_pem, key_pem)) = current
+        && not_after(&cert_pem)? > renew_at
+    {
+        return Ok(ServerCert {
+            cert_pem: format!("{}{}", cert_pem, ca_chain),
+            key_pem,
+        });
+    }
+
+    let key = KeyPair::generate().map_err(|e| e.to_string())?;
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]).map_err(|e| e.to_string())?;
+    params.subject_alt_names.push(SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    params.subject_alt_names.push(SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    params.distinguished_name = name("Server Tray localhost");
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    set_validity(&mut params, LEAF_DAYS);
+    let cert = params.signed_by(&key, &ca_cert, &ca_key).map_err(|e| e.to_string())?;
+
+    write(&dir.join(LEAF_CERT), &cert.pem())?;
+    write(&dir.join(LEAF_KEY), &key.serialize_pem())?;
+    log::write_log_line("Issued a new HTTPS certificate");
+    Ok(ServerCert {
+        cert_pem: format!("{}{}", cert.pem(), ca_chain),
+        key_pem: key.serialize_pem(),
+    })
+}
+
+fn load_or_create_ca(dir: &Path) -> Result<(Certificate, KeyPair), String> {
+    if let Some((cert_pem, key_pem)) = read(&dir.join(CA_CERT))?.zip(read(&dir.join(CA_KEY))?) {
+        let key = KeyPair::from_pem(&key_pem).map_err(|e| format!("{}: {}", CA_KEY, e))?;
+        let params = CertificateParams::from_ca_cert_pem(&cert_pem).map_err(|e| format!("{}: {}", CA_CERT, e))?;
+        // Re-signing yields the same subject and key, which is all a leaf
+        // needs from its issuer; the file on disk is left untouched.
+        let cert = params.self_signed(&key).map_err(|e| e.to_string())?;
+        return Ok((cert, key));
+    }
+
+    let key = KeyPair::generate().map_err(|e| e.to_string())?;
+    let mut params = CertificateParams::default();
+    params.distinguished_name = name("Server Tray Local CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    set_validity(&mut params, CA_DAYS);
+    let cert = params.self_signed(&key).map_err(|e| e.to_string())?;
+
+    write(&dir.join(CA_CERT), &cert.pem())?;
+    write(&dir.join(CA_KEY), &key.serialize_pem())?;
+    log::write_log_line("Created the local certificate authority");
+    Ok((cert, key))
+}
+
+/// Reports the current certificates without creating any.
+pub fn status(dir: &Path) -> Result<TlsStatus, String> {
+    let ca_pem = ca_pem(dir)?;
+    let cert_pem = read(&dir.join(LEAF_CERT))?.ok_or("no HTTPS certificate yet")?;
+    let ca_der = pem_body(&ca_pem)?;
+    Ok(TlsStatus {
+        ca_sha256: hex::encode(Sha256::digest(ca_der)),
+        ca_not_after: not_after(&ca_pem)?,
+        cert_not_after: not_after(&cert_pem)?,
+    })
+}
+
+/// The CA certificate, for installing into the OS or browser trust store.
+pub fn ca_pem(dir: &Path) -> Result<String, String> {
+    read(&dir.join(CA_CERT))?.ok_or_else(|| "no local CA yet; start the server with TLS_MODE set".to_string())
+}
+
+fn not_after(pem: &str) -> Result<DateTime<Utc>, String> {
+    let params = CertificateParams::from_ca_cert_pem(pem).map_err(|e| e.to_string())?;
+    Utc.timestamp_opt(params.not_after.unix_timestamp(), 0)
+        .single()
+        .ok_or_else(|| "certificate expiry out of range".to_string())
+}
+
+fn pem_body(pem: &str) -> Result<Vec<u8>, String> {
+    use base64::{Engine as _, engine::general_purpose};
+
+    let body: String = pem
+        .lines()
+        .filter(|l| !l.starts_with("-----"))
+        .collect();
+    general_purpose::STANDARD.decode(body).map_err(|e| e.to_string())
+}
+
+fn name(common_name: &str) -> DistinguishedName {
+    let mut dn = DistinguishedName::new();
+    dn.push(DnType::CommonName, common_name);
+    dn.push(DnType::OrganizationName, "Server Tray");
+    dn
+}
+
+/// Valid from yesterday (clock skew) for `days`.
+fn set_validity(params: &mut CertificateParams, days: i64) {
+    let ymd = |at: DateTime<Utc>| rcgen::date_time_ymd(at.year(), at.month() as u8, at.day() as u8);
+    params.not_before = ymd(Utc::now() - Duration::days(1));
+    params.not_after = ymd(Utc::now() + Duration::days(days));
+}
+
+fn read(path: &Path) -> Result<Option<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("read {}: {}", path.display(), e)),
+    }
+}
+
+fn write(path: &Path, contents: &str) -> Result<(), String> {
+    write_private(path, contents).map_err(|e| format!("write {}: {}", path.display(), e))
+}
+
+// Routes
+/// `GET tls` (expiry and CA fingerprint) and `GET tls/ca.pem`.
+pub fn routes(dir: PathBuf) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let dir_filter = warp::any().map(move || dir.clone());
+
+    let status = warp::path!("tls")
+        .and(warp::get())
+        .and(dir_filter.clone())
+        .map(|dir: PathBuf| match status(&dir) {
+            Ok(status) => warp::reply::json(&status).into_response(),
+            Err(e) => ApiError::NotFound(e).to_response(),
+        });
+
+    let ca = warp::path!("tls" / "ca.pem")
+        .and(warp::get())
+        .and(dir_filter)
+        .map(|dir: PathBuf| match ca_pem(&dir) {
+            Ok(pem) => {
+                let reply = warp::reply::with_header(pem, "content-type", "application/x-pem-file");
+                warp::reply::with_header(
+                    reply,
+                    "content-disposition",
+                    "attachment; filename=\"server-tray-ca.pem\"",
+                )
+                .into_response()
+            }
+            Err(e) => ApiError::NotFound(e).to_response(),
+        });
+
+    status.or(ca)
+}
@@ -0,0 +1,868 @@
+use crate::auth::{Auth, Principal, Scope};
+use crate::error::ApiError;
+use crate::log;
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use askama::Template;
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    Set,
+};
+use sea_orm_migration::{
+    prelude::{Iden, MigrationName, MigrationTrait, SchemaManager, Table},
+    sea_query,
+    sea_query::ColumnDef,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+use warp::{
+    Filter, Rejection, Reply,
+    http::{StatusCode, Uri, header},
+};
+
+pub const SESSION_COOKIE: &str = "server_tray_session";
+/// Readable by scripts, which echo it in `X-CSRF-Token`.
+pub const CSRF_COOKIE: &str = "server_tray_csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+const SESSION_HOURS: i64 = 12;
+const MIN_PASSWORD_LEN: usize = 8;
+
+// Staff account
+pub mod user {
+    use crate::auth::Scope;
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, serde::Serialize, serde::Deserialize)]
+    #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(16))")]
+    #[serde(rename_all = "snake_case")]
+    pub enum Role {
+        #[sea_orm(string_value = "cashier")]
+        Cashier,
+        #[sea_orm(string_value = "manager")]
+        Manager,
+        #[sea_orm(string_value = "admin")]
+        Admin,
+    }
+
+    impl Role {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                Role::Cashier => "cashier",
+                Role::Manager => "manager",
+                Role::Admin => "admin",
+            }
+        }
+
+        pub fn parse(s: &str) -> Option<Self> {
+            match s {
+                "cashier" => Some(Role::Cashier),
+                "manager" => Some(Role::Manager),
+                "admin" => Some(Role::Admin),
+                _ => None,
+            }
+        }
+
+        /// Cashiers read products and cards, managers also edit products,
+        /// admins can do everything.
+        pub fn grants(&self, scope: Scope) -> bool {
+            match self {
+                Role::Cashier => matches!(scope, Scope::ProductsRead | Scope::CardRead | Scope::CardAge),
+                Role::Manager => scope != Scope::Admin,
+                Role::Admin => true,
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "users")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        #[sea_orm(unique)]
+        pub username: String,
+        /// Argon2id PHC string.
+        #[serde(skip)]
+        pub password_hash: String,
+        pub role: Role,
+        pub created_at: DateTimeUtc,
+        /// `DataCipher::cid_digest` of the staff member's own ID card.
+        #[serde(skip)]
+        pub cid_digest: Option<String>,
+        /// Argon2id hash of the PIN asked for after a card login.
+        #[serde(skip)]
+        pub pin_hash: Option<String>,
+        /// Wrong PINs since the last card login, across taps.
+        #[serde(skip)]
+        pub pin_failures: i32,
+        /// Card logins with a PIN are refused until then.
+        #[serde(skip)]
+        pub pin_locked_until: Option<DateTimeUtc>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+// Browser login session
+pub mod session {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+    #[sea_orm(table_name = "sessions")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        /// SHA-256 of the session cookie value.
+        #[sea_orm(unique)]
+        pub token_hash: String,
+        pub user_id: i32,
+        pub csrf_token: String,
+        pub created_at: DateTimeUtc,
+        pub expires_at: DateTimeUtc,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+use user::Role;
+
+// Migration
+pub struct UserMigration;
+
+impl MigrationName for UserMigration {
+    fn name(&self) -> &str {
+        "m0008_create_users"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for UserMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(Users::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Users::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Users::Username).string().not_null().unique_key())
+                    .col(ColumnDef::new(Users::PasswordHash).string().not_null())
+                    .col(ColumnDef::new(Users::Role).string_len(16).not_null())
+                    .col(ColumnDef::new(Users::CreatedAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(Sessions::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(Sessions::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(Sessions::TokenHash).string().not_null().unique_key())
+                    .col(ColumnDef::new(Sessions::UserId).integer().not_null())
+                    .col(ColumnDef::new(Sessions::CsrfToken).string().not_null())
+                    .col(ColumnDef::new(Sessions::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(Sessions::ExpiresAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_table(Table::drop().table(Sessions::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(Users::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum Users {
+    Table,
+    Id,
+    Username,
+    PasswordHash,
+    Role,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum Sessions {
+    Table,
+    Id,
+    TokenHash,
+    UserId,
+    CsrfToken,
+    CreatedAt,
+    ExpiresAt,
+}
+
+/// A user with a live session.
+#[derive(Clone, Debug)]
+pub struct SignedIn {
+    pub user: user::Model,
+    pub session: session::Model,
+}
+
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| e.to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+// Verified against when the username is unknown, so both cases take as long.
+static DUMMY_HASH: LazyLock<String> = LazyLock::new(|| hash_password("server_tray").unwrap_or_default());
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn random_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+pub async fn any_users(db: &DatabaseConnection) -> Result<bool, DbErr> {
+    Ok(user::Entity::find().count(db).await? > 0)
+}
+
+/// Opens first-run setup. The tray's "Open in browser" carries the code, so the
+/// first admin is created by someone at the tray, or with `users create`, and
+/// not by whichever local client reaches `/setup` first.
+#[derive(Clone)]
+pub struct Setup {
+    code: Arc<String>,
+    open: Arc<AtomicBool>,
+}
+
+impl Default for Setup {
+    fn default() -> Self {
+        Self {
+            code: Arc::new(hex::encode(rand::random::<[u8; 16]>())),
+            open: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Setup {
+    /// Opens setup while there are no users.
+    pub async fn refresh(&self, db: &DatabaseConnection) -> Result<(), DbErr> {
+        self.open.store(!any_users(db).await?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The setup page under `base`, while setup is open.
+    pub fn url(&self, base: &str) -> Option<String> {
+        self.open
+            .load(Ordering::Relaxed)
+            .then(|| format!("{}/setup?code={}", base, self.code))
+    }
+
+    fn accepts(&self, code: Option<&str>) -> bool {
+        self.open.load(Ordering::Relaxed) && code == Some(self.code.as_str())
+    }
+
+    fn close(&self) {
+        self.open.store(false, Ordering::Relaxed);
+    }
+}
+
+pub async fn create_user(
+    db: &DatabaseConnection,
+    username: &str,
+    password: &str,
+    role: Role,
+) -> Result<user::Model, String> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err("username is required".to_string());
+    }
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!("password must be at least {} characters", MIN_PASSWORD_LEN));
+    }
+    let taken = user::Entity::find()
+        .filter(user::Column::Username.eq(username))
+        .one(db)
+        .await
+        .map_err(|e| e.to_string())?;
+    if taken.is_some() {
+        return Err(format!("username {} is taken", username));
+    }
+    user::ActiveModel {
+        username: Set(username.to_string()),
+        password_hash: Set(hash_password(password)?),
+        role: Set(role),
+        created_at: Set(Utc::now()),
+        ..Default::default()
+    }
+    .insert(db)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+pub async fn list_users(db: &DatabaseConnection) -> Result<Vec<user::Model>, DbErr> {
+    user::Entity::find().order_by_asc(user::Column::Username).all(db).await
+}
+
+/// Deletes the user and ends their sessions.
+pub async fn delete_user(db: &DatabaseConnection, id: i32) -> Result<bool, DbErr> {
+    session::Entity::delete_many()
+        .filter(session::Column::UserId.eq(id))
+        .exec(db)
+        .await?;
+    Ok(user::Entity::delete_by_id(id).exec(db).await?.rows_affected > 0)
+}
+
+async fn authenticate(db: &DatabaseConnection, username: &str, password: &str) -> Result<Option<user::Model>, DbErr> {
+    let found = user::Entity::find()
+        .filter(user::Column::Username.eq(username.trim()))
+        .one(db)
+        .await?;
+    Ok(match found {
+        Some(user) if verify_password(password, &user.password_hash) => Some(user),
+        Some(_) => None,
+        None => {
+            verify_password(password, &DUMMY_HASH);
+            None
+        }
+    })
+}
+
+/// Starts a session and returns it with the cookie value, which is not stored.
+pub async fn open_session(db: &DatabaseConnection, user_id: i32) -> Result<(session::Model, String), DbErr> {
+    let now = Utc::now();
+    session::Entity::delete_many()
+        .filter(session::Column::ExpiresAt.lte(now))
+        .exec(db)
+        .await?;
+    let token = random_token();
+    let session = session::ActiveModel {
+        token_hash: Set(hash_token(&token)),
+        user_id: Set(user_id),
+        csrf_token: Set(random_token()),
+        created_at: Set(now),
+        expires_at: Set(now + Duration::hours(SESSION_HOURS)),
+        ..Default::default()
+    }
+    .insert(db)
+    .await?;
+    Ok((session, token))
+}
+
+/// Looks up the live session for a cookie value.
+pub async fn find_session(db: &DatabaseConnection, token: &str) -> Result<Option<SignedIn>, DbErr> {
+    let found = session::Entity::find()
+        .filter(session::Column::TokenHash.eq(hash_token(token)))
+        .filter(session::Column::ExpiresAt.gt(Utc::now()))
+        .one(db)
+        .await?;
+    let Some(session) = found else {
+        return Ok(None);
+    };
+    let user = user::Entity::find_by_id(session.user_id).one(db).await?;
+    Ok(user.map(|user| SignedIn { user, session }))
+}
+
+/// With `secure` the browser only sends them back over HTTPS.
+fn cookie_attributes(secure: bool) -> &'static str {
+    if secure { "Path=/; SameSite=Strict; Secure" } else { "Path=/; SameSite=Strict" }
+}
+
+pub fn session_cookies(token: &str, csrf: &str, expires_at: DateTime<Utc>, secure: bool) -> [String; 2] {
+    let max_age = (expires_at - Utc::now()).num_seconds().max(0);
+    let attributes = cookie_attributes(secure);
+    [
+        format!("{}={}; {}; HttpOnly; Max-Age={}", SESSION_COOKIE, token, attributes, max_age),
+        format!("{}={}; {}; Max-Age={}", CSRF_COOKIE, csrf, attributes, max_age),
+    ]
+}
+
+/// A CSRF cookie for a visitor who is not signed in, so that the counter page
+/// can show it is a page of this app when it claims a staff card.
+pub fn visitor_cookie(secure: bool) -> String {
+    format!(
+        "{}={}; {}",
+        CSRF_COOKIE,
+        hex::encode(rand::random::<[u8; 32]>()),
+        cookie_attributes(secure)
+    )
+}
+
+fn cleared_cookies(secure: bool) -> [String; 2] {
+    let attributes = cookie_attributes(secure);
+    [
+        format!("{}=; {}; HttpOnly; Max-Age=0", SESSION_COOKIE, attributes),
+        format!("{}=; {}; Max-Age=0", CSRF_COOKIE, attributes),
+    ]
+}
+
+fn redirect_with_cookies(to: &'static str, cookies: [String; 2]) -> warp::reply::Response {
+    let mut res = warp::redirect::see_other(Uri::from_static(to)).into_response();
+    for cookie in cookies {
+        if let Ok(value) = cookie.parse() {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+    }
+    res
+}
+
+pub fn redirect(to: &'static str) -> warp::reply::Response {
+    warp::redirect::see_other(Uri::from_static(to)).into_response()
+}
+
+// Routes
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate {
+    failed: bool,
+}
+
+#[derive(Template)]
+#[template(path = "setup.html")]
+struct SetupTemplate<'a> {
+    /// `None` unless the page was opened with the setup code.
+    code: Option<&'a str>,
+    error: Option<&'a str>,
+}
+
+#[derive(serde::Deserialize)]
+struct LoginQuery {
+    #[serde(default)]
+    failed: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+#[derive(serde::Deserialize)]
+struct SetupQuery {
+    code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct SetupForm {
+    code: String,
+    username: String,
+    password: String,
+    confirm: String,
+}
+
+#[derive(serde::Deserialize)]
+struct LogoutForm {
+    csrf: String,
+}
+
+#[derive(serde::Deserialize)]
+struct NewUser {
+    username: String,
+    password: String,
+    role: Role,
+}
+
+#[derive(serde::Serialize)]
+struct SessionInfo<'a> {
+    user: &'a user::Model,
+    csrf_token: &'a str,
+}
+
+/// Login, logout and first-run setup pages, plus `api/session` and
+/// `api/users`.
+pub fn routes(auth: Auth, setup: Setup) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let db = auth.db().clone();
+    let db_filter = warp::any().map(move || db.clone());
+    let setup_filter = warp::any().map(move || setup.clone());
+    let secure = auth.secure_cookies();
+    let secure_filter = warp::any().map(move || secure);
+
+    let login_page = warp::path!("login")
+        .and(warp::get())
+        .and(warp::query::<LoginQuery>())
+        .and(warp::cookie::optional::<String>(CSRF_COOKIE))
+        .and(db_filter.clone())
+        .and(secure_filter)
+        .and_then(render_login);
+
+    let login = warp::path!("login")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(db_filter.clone())
+        .and(secure_filter)
+        .and_then(post_login);
+
+    // A plain form post, so the CSRF token comes in the form rather than the
+    // header `Auth::principal` checks.
+    let logout = warp::path!("logout")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(db_filter.clone())
+        .and(secure_filter)
+        .and_then(post_logout);
+
+    let setup_page = warp::path!("setup")
+        .and(warp::get())
+        .and(warp::query::<SetupQuery>())
+        .and(db_filter.clone())
+        .and(setup_filter.clone())
+        .and_then(render_setup);
+
+    let setup = warp::path!("setup")
+        .and(warp::post())
+        .and(warp::addr::remote())
+        .and(warp::body::form())
+        .and(db_filter.clone())
+        .and(setup_filter)
+        .and(secure_filter)
+        .and_then(post_setup);
+
+    let current = warp::path!("api" / "session")
+        .and(warp::get())
+        .and(auth.principal())
+        .and_then(get_session);
+
+    let admin = auth.require(Scope::Admin);
+
+    let list = warp::path!("api" / "users")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(db_filter.clone())
+        .and_then(get_users);
+
+    let create = warp::path!("api" / "users")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(db_filter.clone())
+        .and_then(post_user);
+
+    let delete = warp::path!("api" / "users" / i32)
+        .and(warp::delete())
+        .and(admin)
+        .and(auth.principal())
+        .and(db_filter)
+        .and_then(remove_user);
+
+    login_page
+        .or(login)
+        .or(logout)
+        .or(setup_page)
+        .or(setup)
+        .or(current)
+        .or(list)
+        .or(create)
+        .or(delete)
+}
+
+async fn render_login(
+    query: LoginQuery,
+    csrf: Option<String>,
+    db: DatabaseConnection,
+    secure: bool,
+) -> Result<impl Reply, Rejection> {
+    if !any_users(&db).await.map_err(ApiError::from)? {
+        return Ok(redirect("/setup"));
+    }
+    let page = LoginTemplate { failed: query.failed };
+    let mut res = warp::reply::html(page.render().unwrap()).into_response();
+    if csrf.is_none()
+        && let Ok(value) = visitor_cookie(secure).parse()
+    {
+        res.headers_mut().append(header::SET_COOKIE, value);
+    }
+    Ok(res)
+}
+
+async fn post_login(form: LoginForm, db: DatabaseConnection, secure: bool) -> Result<impl Reply, Rejection> {
+    let Some(user) = authenticate(&db, &form.username, &form.password)
+        .await
+        .map_err(ApiError::from)?
+    else {
+        log::write_log_line(&format!("Failed login for {}", form.username.trim()));
+        return Ok(redirect("/login?failed=true"));
+    };
+    let (session, token) = open_session(&db, user.id).await.map_err(ApiError::from)?;
+    log::write_log_line(&format!("{} signed in", user.username));
+    Ok(redirect_with_cookies(
+        "/",
+        session_cookies(&token, &session.csrf_token, session.expires_at, secure),
+    ))
+}
+
+async fn post_logout(
+    form: LogoutForm,
+    cookie: Option<String>,
+    db: DatabaseConnection,
+    secure: bool,
+) -> Result<impl Reply, Rejection> {
+    let signed_in = match cookie {
+        Some(cookie) => find_session(&db, &cookie).await.map_err(ApiError::from)?,
+        None => None,
+    };
+    if let Some(signed_in) = signed_in {
+        if form.csrf != signed_in.session.csrf_token {
+            return Err(ApiError::Forbidden("CSRF token missing or invalid".to_string()).into());
+        }
+        session::Entity::delete_by_id(signed_in.session.id)
+            .exec(&db)
+            .await
+            .map_err(ApiError::from)?;
+        log::write_log_line(&format!("{} signed out", signed_in.user.username));
+    }
+    Ok(redirect_with_cookies("/login", cleared_cookies(secure)))
+}
+
+/// Without the code from the tray, explains how to open setup instead of
+/// showing the form.
+async fn render_setup(query: SetupQuery, db: DatabaseConnection, setup: Setup) -> Result<impl Reply, Rejection> {
+    if any_users(&db).await.map_err(ApiError::from)? {
+        setup.close();
+        return Ok(redirect("/login"));
+    }
+    let code = query.code.as_deref().filter(|code| setup.accepts(Some(code)));
+    let page = SetupTemplate { code, error: None };
+    Ok(warp::reply::html(page.render().unwrap()).into_response())
+}
+
+/// Creates the first admin. Only works while there are no users, only from
+/// this machine and only with the setup code.
+async fn post_setup(
+    remote: Option<SocketAddr>,
+    form: SetupForm,
+    db: DatabaseConnection,
+    setup: Setup,
+    secure: bool,
+) -> Result<impl Reply, Rejection> {
+    if !remote.is_some_and(|addr| addr.ip().is_loopback()) {
+        return Err(ApiError::Forbidden("setup is only available on this machine".to_string()).into());
+    }
+    if any_users(&db).await.map_err(ApiError::from)? {
+        setup.close();
+        return Ok(redirect("/login"));
+    }
+    if !setup.accepts(Some(&form.code)) {
+        return Err(ApiError::Forbidden("open setup from the tray icon".to_string()).into());
+    }
+    let failed = |error: &str| {
+        let page = SetupTemplate { code: Some(&form.code), error: Some(error) };
+        warp::reply::with_status(warp::reply::html(page.render().unwrap()), StatusCode::BAD_REQUEST).into_response()
+    };
+    if form.password != form.confirm {
+        return Ok(failed("Passwords do not match"));
+    }
+    let admin = match create_user(&db, &form.username, &form.password, Role::Admin).await {
+        Ok(admin) => admin,
+        Err(e) => return Ok(failed(&e)),
+    };
+    setup.close();
+    log::write_log_line(&format!("Admin {} created by first-run setup", admin.username));
+    let (session, token) = open_session(&db, admin.id).await.map_err(ApiError::from)?;
+    Ok(redirect_with_cookies(
+        "/",
+        session_cookies(&token, &session.csrf_token, session.expires_at, secure),
+    ))
+}
+
+async fn get_session(principal: Principal) -> Result<impl Reply, Rejection> {
+    match principal {
+        Principal::User(signed_in) => Ok(warp::reply::json(&SessionInfo {
+            user: &signed_in.user,
+            csrf_token: &signed_in.session.csrf_token,
+        })),
+        _ => Err(ApiError::Unauthorized("not signed in".to_string()).into()),
+    }
+}
+
+async fn get_users(db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let users = list_users(&db).await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&users))
+}
+
+async fn post_user(new: NewUser, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    let user = create_user(&db, &new.username, &new.password, new.role)
+        .await
+        .map_err(ApiError::BadRequest)?;
+    Ok(warp::reply::with_status(warp::reply::json(&user), StatusCode::CREATED))
+}
+
+async fn remove_user(id: i32, principal: Principal, db: DatabaseConnection) -> Result<impl Reply, Rejection> {
+    if let Principal::User(signed_in) = &principal
+        && signed_in.user.id == id
+    {
+        return Err(ApiError::BadRequest("cannot delete the signed-in user".to_string()).into());
+    }
+    if !delete_user(&db, id).await.map_err(ApiError::from)? {
+        return Err(ApiError::NotFound("user not found".to_string()).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthMode;
+    use crate::{error, migrator};
+
+    fn app(db: DatabaseConnection) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone {
+        routes(Auth::new(db, AuthMode::Remote), Setup::default()).recover(error::recover)
+    }
+
+    fn cookie_value<'a>(res: &'a warp::http::Response<warp::hyper::body::Bytes>, name: &str) -> &'a str {
+        res.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .find_map(|v| v.strip_prefix(name)?.strip_prefix('='))
+            .and_then(|v| v.split(';').next())
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn sign_in_then_sign_out_with_the_page_form() {
+        let db = migrator::test_db().await;
+        create_user(&db, "alice", "correct horse", Role::Cashier).await.unwrap();
+        let routes = app(db.clone());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/login")
+            .remote_addr(([127, 0, 0, 1], 40000).into())
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body("username=alice&password=correct+horse")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()[header::LOCATION], "/");
+        let token = cookie_value(&res, SESSION_COOKIE).to_string();
+        let csrf = cookie_value(&res, CSRF_COOKIE).to_string();
+        assert!(find_session(&db, &token).await.unwrap().is_some());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/logout")
+            .remote_addr(([127, 0, 0, 1], 40000).into())
+            .header(header::COOKIE, format!("{}={}; {}={}", SESSION_COOKIE, token, CSRF_COOKIE, csrf))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(format!("csrf={}", csrf))
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(res.headers()[header::LOCATION], "/login");
+        assert_eq!(cookie_value(&res, SESSION_COOKIE), "");
+        assert!(find_session(&db, &token).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn sign_out_needs_the_session_csrf_token() {
+        let db = migrator::test_db().await;
+        let user = create_user(&db, "bob", "correct horse", Role::Cashier).await.unwrap();
+        let (_, token) = open_session(&db, user.id).await.unwrap();
+        let routes = app(db.clone());
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/logout")
+            .header(header::COOKIE, format!("{}={}", SESSION_COOKIE, token))
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body("csrf=wrong")
+            .reply(&routes)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(find_session(&db, &token).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn setup_needs_the_code_the_tray_opens_it_with() {
+        let db = migrator::test_db().await;
+        let setup = Setup::default();
+        setup.refresh(&db).await.unwrap();
+        let routes = routes(Auth::new(db.clone(), AuthMode::Remote), setup.clone()).recover(error::recover);
+        let post = |code: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/setup")
+                .remote_addr(([127, 0, 0, 1], 40000).into())
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(format!("code={}&username=admin&password=correct+horse&confirm=correct+horse", code))
+        };
+
+        assert_eq!(post("guess").reply(&routes).await.status(), StatusCode::FORBIDDEN);
+        assert!(!any_users(&db).await.unwrap());
+
+        let url = setup.url("").unwrap();
+        let code = url.strip_prefix("/setup?code=").unwrap();
+        assert_eq!(post(code).reply(&routes).await.status(), StatusCode::SEE_OTHER);
+        assert!(any_users(&db).await.unwrap());
+        assert_eq!(setup.url(""), None);
+    }
+
+    #[test]
+    fn cookies_are_secure_only_over_https() {
+        let expires_at = Utc::now() + chrono::Duration::hours(1);
+        let cookies = [session_cookies("t", "c", expires_at, true), cleared_cookies(true)].concat();
+        assert!(cookies.iter().chain([&visitor_cookie(true)]).all(|c| c.contains("; Secure")));
+        let cookies = [session_cookies("t", "c", expires_at, false), cleared_cookies(false)].concat();
+        assert!(!cookies.iter().chain([&visitor_cookie(false)]).any(|c| c.contains("Secure")));
+    }
+
+    #[test]
+    fn roles_grant_widening_scopes() {
+        let granted = |role: Role| Scope::ALL.into_iter().filter(|s| role.grants(*s)).collect::<Vec<_>>();
+        assert_eq!(granted(Role::Cashier), [Scope::ProductsRead, Scope::CardRead, Scope::CardAge]);
+        assert_eq!(
+            granted(Role::Manager),
+            [Scope::ProductsRead, Scope::ProductsWrite, Scope::CardRead, Scope::CardAge]
+        );
+        assert_eq!(granted(Role::Admin), Scope::ALL);
+    }
+
+    #[tokio::test]
+    async fn authenticates_only_the_right_password() {
+        let db = migrator::test_db().await;
+        let user = create_user(&db, " carol ", "correct horse", Role::Manager).await.unwrap();
+        assert_eq!(user.username, "carol");
+        assert!(create_user(&db, "carol", "another horse", Role::Cashier).await.is_err());
+        assert!(create_user(&db, "dave", "short", Role::Cashier).await.is_err());
+
+        let found = authenticate(&db, "carol", "correct horse").await.unwrap();
+        assert_eq!(found.map(|u| u.id), Some(user.id));
+        assert!(authenticate(&db, "carol", "wrong horse").await.unwrap().is_none());
+        assert!(authenticate(&db, "nobody", "correct horse").await.unwrap().is_none());
+    }
+}
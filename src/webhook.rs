@@ -0,0 +1,581 @@
+use crate::auth::{Auth, Scope};
+use crate::crypto::DataCipher;
+use crate::error::ApiError;
+use crate::events::{EventBus, EventFilter};
+use crate::log;
+use crate::signing::{JWS_HEADER, Signer};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    QuerySelect, Set,
+};
+use sea_orm_migration::{
+    prelude::{Iden, Index, MigrationName, MigrationTrait, SchemaManager, Table},
+    sea_query,
+    sea_query::ColumnDef,
+};
+use sha2::Sha256;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{Notify, broadcast::error::RecvError};
+use warp::{Filter, Rejection, Reply, http::StatusCode};
+
+const MAX_ATTEMPTS: i32 = 10;
+const BASE_BACKOFF_SECS: i64 = 5;
+const MAX_BACKOFF_SECS: i64 = 3600;
+const BATCH_SIZE: u64 = 20;
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Webhook target entity
+pub mod target {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "webhook_targets")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub url: String,
+        /// Signing secret, encrypted with the data key.
+        #[serde(skip)]
+        pub secret: String,
+        /// Comma-separated event types; empty means all.
+        pub events: String,
+        /// Comma-separated reader names; empty means all.
+        pub readers: String,
+        pub enabled: bool,
+        pub created_at: DateTimeUtc,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+// Outbox entity: one row per (event, target) pair
+pub mod delivery {
+    use sea_orm::entity::prelude::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, EnumIter, DeriveActiveEnum, serde::Serialize, serde::Deserialize)]
+    #[sea_orm(rs_type = "String", db_type = "String(StringLen::N(16))")]
+    #[serde(rename_all = "snake_case")]
+    pub enum Status {
+        #[sea_orm(string_value = "pending")]
+        Pending,
+        #[sea_orm(string_value = "delivered")]
+        Delivered,
+        #[sea_orm(string_value = "failed")]
+        Failed,
+    }
+
+    #[derive(Clone, Debug, PartialEq, DeriveEntityModel, serde::Serialize)]
+    #[sea_orm(table_name = "webhook_deliveries")]
+    pub struct Model {
+        #[sea_orm(primary_key)]
+        pub id: i32,
+        pub target_id: i32,
+        pub event_id: i64,
+        pub event_type: String,
+        /// Event JSON, encrypted with the data key.
+        #[sea_orm(column_type = "Text")]
+        #[serde(skip)]
+        pub payload: String,
+        pub status: Status,
+        pub attempts: i32,
+        pub next_attempt_at: DateTimeUtc,
+        pub last_status: Option<i32>,
+        pub last_error: Option<String>,
+        pub created_at: DateTimeUtc,
+        pub delivered_at: Option<DateTimeUtc>,
+    }
+
+    #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+    pub enum Relation {}
+
+    impl ActiveModelBehavior for ActiveModel {}
+}
+
+use delivery::Status;
+
+// Migration
+pub struct WebhookMigration;
+
+impl MigrationName for WebhookMigration {
+    fn name(&self) -> &str {
+        "m0002_create_webhooks"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for WebhookMigration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(WebhookTargets::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(WebhookTargets::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(WebhookTargets::Url).string().not_null())
+                    .col(ColumnDef::new(WebhookTargets::Secret).string().not_null())
+                    .col(ColumnDef::new(WebhookTargets::Events).string().not_null().default(""))
+                    .col(ColumnDef::new(WebhookTargets::Readers).string().not_null().default(""))
+                    .col(ColumnDef::new(WebhookTargets::Enabled).boolean().not_null().default(true))
+                    .col(ColumnDef::new(WebhookTargets::CreatedAt).timestamp_with_time_zone().not_null())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_table(
+                Table::create()
+                    .table(WebhookDeliveries::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(WebhookDeliveries::Id)
+                            .integer()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(WebhookDeliveries::TargetId).integer().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::EventId).big_integer().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::EventType).string().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::Payload).text().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::Status).string_len(16).not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::Attempts).integer().not_null().default(0))
+                    .col(ColumnDef::new(WebhookDeliveries::NextAttemptAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::LastStatus).integer())
+                    .col(ColumnDef::new(WebhookDeliveries::LastError).string())
+                    .col(ColumnDef::new(WebhookDeliveries::CreatedAt).timestamp_with_time_zone().not_null())
+                    .col(ColumnDef::new(WebhookDeliveries::DeliveredAt).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                Index::create()
+                    .name("idx_webhook_deliveries_due")
+                    .table(WebhookDeliveries::Table)
+                    .col(WebhookDeliveries::Status)
+                    .col(WebhookDeliveries::NextAttemptAt)
+                    .if_not_exists()
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), sea_orm_migration::DbErr> {
+        manager
+            .drop_table(Table::drop().table(WebhookDeliveries::Table).to_owned())
+            .await?;
+        manager
+            .drop_table(Table::drop().table(WebhookTargets::Table).to_owned())
+            .await
+    }
+}
+
+#[derive(Iden)]
+enum WebhookTargets {
+    Table,
+    Id,
+    Url,
+    Secret,
+    Events,
+    Readers,
+    Enabled,
+    CreatedAt,
+}
+
+#[derive(Iden)]
+enum WebhookDeliveries {
+    Table,
+    Id,
+    TargetId,
+    EventId,
+    EventType,
+    Payload,
+    Status,
+    Attempts,
+    NextAttemptAt,
+    LastStatus,
+    LastError,
+    CreatedAt,
+    DeliveredAt,
+}
+
+// Delivery
+#[derive(Clone)]
+pub struct Webhooks {
+    db: DatabaseConnection,
+    cipher: DataCipher,
+    signer: Signer,
+    wake: Arc<Notify>,
+}
+
+impl Webhooks {
+    pub fn new(db: DatabaseConnection, cipher: DataCipher, signer: Signer) -> Self {
+        Self {
+            db,
+            cipher,
+            signer,
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Spawns the outbox writer and the delivery worker on the current runtime.
+    pub fn spawn(&self, bus: EventBus) {
+        tokio::spawn(enqueue_events(self.clone(), bus));
+        tokio::spawn(deliver_loop(self.clone()));
+    }
+}
+
+async fn enqueue_events(hooks: Webhooks, bus: EventBus) {
+    let mut rx = bus.subscribe();
+    loop {
+        let event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(n)) => {
+                log::write_log_line(&format!("Webhook outbox lagged, {} events dropped", n));
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        let targets = match target::Entity::find()
+            .filter(target::Column::Enabled.eq(true))
+            .all(&hooks.db)
+            .await
+        {
+            Ok(targets) => targets,
+            Err(e) => {
+                log::write_log_line(&format!("Webhook target lookup failed: {}", e));
+                continue;
+            }
+        };
+
+        let Ok(payload) = serde_json::to_string(&*event) else {
+            continue;
+        };
+        let payload = hooks.cipher.encrypt(&payload);
+        let now = Utc::now();
+        let mut queued = false;
+        for t in targets {
+            let filter = EventFilter::from_query(Some(&t.readers), Some(&t.events));
+            if !filter.matches(&event.event) {
+                continue;
+            }
+            let row = delivery::ActiveModel {
+                target_id: Set(t.id),
+                event_id: Set(event.id as i64),
+                event_type: Set(event.event.kind().to_string()),
+                payload: Set(payload.clone()),
+                status: Set(Status::Pending),
+                attempts: Set(0),
+                next_attempt_at: Set(now),
+                created_at: Set(now),
+                ..Default::default()
+            };
+            match row.insert(&hooks.db).await {
+                Ok(_) => queued = true,
+                Err(e) => log::write_log_line(&format!("Webhook enqueue failed: {}", e)),
+            }
+        }
+        if queued {
+            hooks.wake.notify_one();
+        }
+    }
+}
+
+async fn deliver_loop(hooks: Webhooks) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(c) => c,
+        Err(e) => {
+            log::write_log_line(&format!("Webhook client init failed: {}", e));
+            return;
+        }
+    };
+
+    loop {
+        match deliver_due(&hooks.db, &hooks.cipher, &hooks.signer, &client).await {
+            // A full batch probably means more is due; go again right away.
+            Ok(n) if n as u64 == BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(e) => log::write_log_line(&format!("Webhook delivery pass failed: {}", e)),
+        }
+
+        tokio::select! {
+            _ = hooks.wake.notified() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+async fn deliver_due(
+    db: &DatabaseConnection,
+    cipher: &DataCipher,
+    signer: &Signer,
+    client: &reqwest::Client,
+) -> Result<usize, DbErr> {
+    let due = delivery::Entity::find()
+        .filter(delivery::Column::Status.eq(Status::Pending))
+        .filter(delivery::Column::NextAttemptAt.lte(Utc::now()))
+        .order_by_asc(delivery::Column::Id)
+        .limit(BATCH_SIZE)
+        .all(db)
+        .await?;
+    let count = due.len();
+
+    for d in due {
+        let outcome = match (
+            target::Entity::find_by_id(d.target_id).one(db).await?,
+            cipher.decrypt(&d.payload),
+        ) {
+            (Some(t), Ok(body)) => match cipher.decrypt(&t.secret) {
+                Ok(secret) => post(client, signer, &t, &secret, &d, body).await,
+                Err(e) => Err((None, e)),
+            },
+            (None, _) => Err((None, "target deleted".to_string())),
+            (_, Err(e)) => Err((None, e)),
+        };
+
+        let attempts = d.attempts + 1;
+        let mut row: delivery::ActiveModel = d.into();
+        row.attempts = Set(attempts);
+        match outcome {
+            Ok(status) => {
+                row.status = Set(Status::Delivered);
+                row.last_status = Set(Some(status));
+                row.last_error = Set(None);
+                row.delivered_at = Set(Some(Utc::now()));
+            }
+            Err((status, error)) => {
+                row.last_status = Set(status);
+                row.last_error = Set(Some(error));
+                if attempts >= MAX_ATTEMPTS {
+                    row.status = Set(Status::Failed);
+                } else {
+                    row.next_attempt_at = Set(Utc::now() + backoff(attempts));
+                }
+            }
+        }
+        row.update(db).await?;
+    }
+
+    Ok(count)
+}
+
+fn backoff(attempts: i32) -> chrono::Duration {
+    let secs = BASE_BACKOFF_SECS
+        .saturating_mul(1i64 << (attempts - 1).clamp(0, 20))
+        .min(MAX_BACKOFF_SECS);
+    chrono::Duration::seconds(secs)
+}
+
+/// `t=<unix seconds>,v1=<hex HMAC-SHA256 of "<t>.<body>">`
+fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn post(
+    client: &reqwest::Client,
+    signer: &Signer,
+    target: &target::Model,
+    secret: &str,
+    d: &delivery::Model,
+    body: String,
+) -> Result<i32, (Option<i32>, String)> {
+    let signature = sign(secret, Utc::now().timestamp(), &body);
+    let jws = signer.sign(body.as_bytes()).detached();
+    let res = client
+        .post(&target.url)
+        .header("content-type", "application/json")
+        .header("x-server-tray-event", &d.event_type)
+        .header("x-server-tray-delivery", d.id.to_string())
+        .header("x-server-tray-signature", signature)
+        .header(JWS_HEADER, jws)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| (None, e.to_string()))?;
+
+    let status = res.status().as_u16() as i32;
+    if res.status().is_success() {
+        Ok(status)
+    } else {
+        Err((Some(status), format!("HTTP {}", status)))
+    }
+}
+
+// Routes
+#[derive(serde::Deserialize)]
+struct NewTarget {
+    url: String,
+    secret: Option<String>,
+    #[serde(default)]
+    events: Vec<String>,
+    #[serde(default)]
+    readers: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct CreatedTarget {
+    #[serde(flatten)]
+    target: target::Model,
+    secret: String,
+}
+
+#[derive(serde::Deserialize)]
+struct DeliveryQuery {
+    status: Option<Status>,
+    target_id: Option<i32>,
+    limit: Option<u64>,
+}
+
+pub fn routes(hooks: Webhooks, auth: Auth) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let admin = auth.require(Scope::Admin);
+    let hooks_filter = warp::any().map(move || hooks.clone());
+
+    let list = warp::path!("webhooks")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(hooks_filter.clone())
+        .and_then(list_targets);
+
+    let create = warp::path!("webhooks")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(hooks_filter.clone())
+        .and_then(create_target);
+
+    let delete = warp::path!("webhooks" / i32)
+        .and(warp::delete())
+        .and(admin.clone())
+        .and(hooks_filter.clone())
+        .and_then(delete_target);
+
+    let deliveries = warp::path!("webhooks" / "deliveries")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(warp::query::<DeliveryQuery>())
+        .and(hooks_filter.clone())
+        .and_then(list_deliveries);
+
+    let retry = warp::path!("webhooks" / "deliveries" / i32 / "retry")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(hooks_filter)
+        .and_then(retry_delivery);
+
+    list.or(create).or(delete).or(deliveries).or(retry)
+}
+
+async fn list_targets(hooks: Webhooks) -> Result<impl Reply, Rejection> {
+    let targets = target::Entity::find().all(&hooks.db).await.map_err(ApiError::from)?;
+    Ok(warp::reply::json(&targets))
+}
+
+async fn create_target(new: NewTarget, hooks: Webhooks) -> Result<impl Reply, Rejection> {
+    if !(new.url.starts_with("http://") || new.url.starts_with("https://")) {
+        return Err(ApiError::BadRequest("url must be http(s)".to_string()).into());
+    }
+    let secret = new
+        .secret
+        .unwrap_or_else(|| hex::encode(rand::random::<[u8; 32]>()));
+
+    let row = target::ActiveModel {
+        url: Set(new.url),
+        secret: Set(hooks.cipher.encrypt(&secret)),
+        events: Set(new.events.join(",")),
+        readers: Set(new.readers.join(",")),
+        enabled: Set(true),
+        created_at: Set(Utc::now()),
+        ..Default::default()
+    };
+    let target = row.insert(&hooks.db).await.map_err(ApiError::from)?;
+    let body = CreatedTarget { target, secret };
+    Ok(warp::reply::with_status(warp::reply::json(&body), StatusCode::CREATED))
+}
+
+async fn delete_target(id: i32, hooks: Webhooks) -> Result<impl Reply, Rejection> {
+    delivery::Entity::delete_many()
+        .filter(delivery::Column::TargetId.eq(id))
+        .filter(delivery::Column::Status.eq(Status::Pending))
+        .exec(&hooks.db)
+        .await
+        .map_err(ApiError::from)?;
+    let res = target::Entity::delete_by_id(id)
+        .exec(&hooks.db)
+        .await
+        .map_err(ApiError::from)?;
+    if res.rows_affected == 0 {
+        return Err(ApiError::NotFound("webhook not found".to_string()).into());
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn list_deliveries(query: DeliveryQuery, hooks: Webhooks) -> Result<impl Reply, Rejection> {
+    let mut find = delivery::Entity::find().order_by_desc(delivery::Column::Id);
+    if let Some(status) = query.status {
+        find = find.filter(delivery::Column::Status.eq(status));
+    }
+    if let Some(target_id) = query.target_id {
+        find = find.filter(delivery::Column::TargetId.eq(target_id));
+    }
+    let rows = find
+        .limit(query.limit.unwrap_or(100).min(1000))
+        .all(&hooks.db)
+        .await
+        .map_err(ApiError::from)?;
+    Ok(warp::reply::json(&rows))
+}
+
+async fn retry_delivery(id: i32, hooks: Webhooks) -> Result<impl Reply, Rejection> {
+    let row = delivery::Entity::find_by_id(id)
+        .one(&hooks.db)
+        .await
+        .map_err(ApiError::from)?
+        .ok_or_else(|| ApiError::NotFound("delivery not found".to_string()))?;
+    let mut row: delivery::ActiveModel = row.into();
+    row.status = Set(Status::Pending);
+    row.next_attempt_at = Set(Utc::now());
+    let row = row.update(&hooks.db).await.map_err(ApiError::from)?;
+    hooks.wake.notify_one();
+    Ok(warp::reply::json(&row))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_the_timestamp_and_body() {
+        assert_eq!(
+            sign("whsec", 1_700_000_000, r#"{"a":1}"#),
+            "t=1700000000,v1=8ad37ba156048ae0e0a5533c75cdf26fee88b07f93cb57ee4c80adb053012032"
+        );
+        assert_ne!(sign("whsec", 1_700_000_001, r#"{"a":1}"#), sign("whsec", 1_700_000_000, r#"{"a":1}"#));
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_an_hour() {
+        let secs: Vec<i64> = (1..=MAX_ATTEMPTS).map(|n| backoff(n).num_seconds()).collect();
+        assert_eq!(secs, [5, 10, 20, 40, 80, 160, 320, 640, 1280, 2560]);
+        assert_eq!(backoff(0).num_seconds(), BASE_BACKOFF_SECS);
+        assert_eq!(backoff(11).num_seconds(), MAX_BACKOFF_SECS);
+        assert_eq!(backoff(i32::MAX).num_seconds(), MAX_BACKOFF_SECS);
+    }
+}